@@ -1,15 +1,125 @@
 // 128k, the amount of memory in a standard Vulcan machine
 pub const MEM_SIZE: u32 = 128 * 1024;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Word(u32);
 
+impl Word {
+    /// The bits that differ between two words, for highlighting what an
+    /// instruction changed
+    pub fn changed_bits(self, other: Word) -> Word { Word::from(self.0 ^ other.0) }
+
+    /// The word as a 24-bit two's complement number, sign-extended from bit 23
+    ///
+    /// ```
+    /// # use vulcan_emu::address::Word;
+    /// assert_eq!(Word::from(0x7fffff).as_signed(), 8388607);
+    /// assert_eq!(Word::from(0xfffffe).as_signed(), -2);
+    /// ```
+    pub fn as_signed(self) -> i32 {
+        ((self.0 << 8) as i32) >> 8
+    }
+
+    /// The word's 24 bits as they are
+    ///
+    /// ```
+    /// # use vulcan_emu::address::Word;
+    /// assert_eq!(Word::from(0xfffffe).as_unsigned(), 0xfffffe);
+    /// ```
+    pub fn as_unsigned(self) -> u32 { self.0 }
+
+    /// Whether the sign bit, bit 23, is set
+    ///
+    /// ```
+    /// # use vulcan_emu::address::Word;
+    /// assert!(Word::from(0x800000).is_negative());
+    /// assert!(!Word::from(0x7fffff).is_negative());
+    /// ```
+    pub fn is_negative(self) -> bool { self.0 & 0x800000 != 0 }
+
+    /// The address `delta` bytes away, for relative branches. Wraps around
+    /// at 24 bits, so a branch backwards from 0 lands at the top of the
+    /// address space, and memory folds that onto its own size.
+    ///
+    /// ```
+    /// # use vulcan_emu::address::Word;
+    /// assert_eq!(Word::from(0x400).offset(-3), Word::from(0x3fd));
+    /// assert_eq!(Word::from(1).offset(-2), Word::from(0xffffff));
+    /// ```
+    pub fn offset(self, delta: i32) -> Word {
+        Word::from(self.0.wrapping_add(delta as u32))
+    }
+
+    /// Adds two words, or `None` if the sum doesn't fit in 24 bits
+    pub fn checked_add(self, rhs: Word) -> Option<Word> { Self::checked(self.0.checked_add(rhs.0)) }
+
+    /// Subtracts two words, or `None` if the difference would be negative
+    pub fn checked_sub(self, rhs: Word) -> Option<Word> { Self::checked(self.0.checked_sub(rhs.0)) }
+
+    /// Multiplies two words, or `None` if the product doesn't fit in 24 bits
+    pub fn checked_mul(self, rhs: Word) -> Option<Word> { Self::checked(self.0.checked_mul(rhs.0)) }
+
+    /// Adds two words, stopping at 0xffffff instead of wrapping
+    pub fn saturating_add(self, rhs: Word) -> Word { Word((self.0 + rhs.0).min(0xffffff)) }
+
+    /// Subtracts two words, stopping at 0 instead of wrapping
+    pub fn saturating_sub(self, rhs: Word) -> Word { Word(self.0.saturating_sub(rhs.0)) }
+
+    /// The word holding `a`, or an error if it's more than 24 bits, where
+    /// `From<u32>` would quietly drop the high bits. (The blanket impl that
+    /// comes with `From<u32>` keeps this from being `TryFrom<u32>`.)
+    pub fn try_from_u32(a: u32) -> Result<Word, OutOfRange> {
+        if a > 0xffffff { Err(OutOfRange(a as i64)) } else { Ok(Word(a)) }
+    }
+
+    fn checked(result: Option<u32>) -> Option<Word> {
+        result.filter(|&r| r <= 0xffffff).map(Word)
+    }
+}
+
 impl From<u32> for Word {
     fn from(a: u32) -> Self { Self(a & 0xffffff) }
 }
 
-impl Into<u32> for Word {
-    fn into(self) -> u32 { self.0 }
+/// A number that doesn't fit in a word's 24 bits
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OutOfRange(pub i64);
+
+impl std::fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} doesn't fit in 24 bits", self.0)
+    }
+}
+
+impl std::error::Error for OutOfRange {}
+
+// Unlike `From<u32>`, which keeps the low 24 bits of anything, this refuses
+// values that don't fit, for tools that would rather catch an overflow. See
+// `Word::try_from_u32` for the unsigned version.
+impl TryFrom<i32> for Word {
+    type Error = OutOfRange;
+    fn try_from(a: i32) -> Result<Self, Self::Error> {
+        if (-0x800000..=0x7fffff).contains(&a) { Ok(Word::from(a as u32)) } else { Err(OutOfRange(a as i64)) }
+    }
+}
+
+impl From<Word> for u32 {
+    fn from(w: Word) -> Self { w.0 }
+}
+
+// Words span 24 bits but memory is only MEM_SIZE bytes, so as an index a
+// word folds onto memory the way the address lines would: the high bits
+// are dropped. This is the only place that decides that.
+impl From<Word> for usize {
+    fn from(w: Word) -> Self { (w.0 & (MEM_SIZE - 1)) as usize }
+}
+
+impl std::fmt::LowerHex for Word {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { std::fmt::LowerHex::fmt(&self.0, f) }
+}
+
+impl std::fmt::UpperHex for Word {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { std::fmt::UpperHex::fmt(&self.0, f) }
 }
 
 impl std::ops::Add<i32> for Word {
@@ -29,6 +139,12 @@ impl std::ops::Sub<Word> for Word {
     fn sub(self, rhs: Word) -> Self::Output { Word(self.0 - rhs.0) }
 }
 
+// Two's complement negation, wrapping at 24 bits like everything else
+impl std::ops::Neg for Word {
+    type Output = Word;
+    fn neg(self) -> Self::Output { Word::from(self.0.wrapping_neg()) }
+}
+
 impl std::ops::SubAssign<i32> for Word {
     fn sub_assign(&mut self, rhs: i32) { *self = *self - rhs; }
 }
@@ -37,6 +153,24 @@ impl std::ops::AddAssign<i32> for Word {
     fn add_assign(&mut self, rhs: i32) { *self = *self + rhs; }
 }
 
+#[test]
+fn test_neg() {
+    assert_eq!(-Word::from(5), Word::from(0xfffffb));
+    assert_eq!(-Word::from(0), Word::from(0));
+    assert_eq!(-Word::from(0x800000), Word::from(0x800000));
+}
+
+#[test]
+fn test_try_from() {
+    assert_eq!(Word::try_from_u32(0xffffff), Ok(Word::from(0xffffff)));
+    assert_eq!(Word::try_from_u32(0x1000000), Err(OutOfRange(0x1000000)));
+    assert_eq!(Word::try_from(-1), Ok(Word::from(0xffffff)));
+    assert_eq!(Word::try_from(-0x800000), Ok(Word::from(0x800000)));
+    assert_eq!(Word::try_from(0x7fffff), Ok(Word::from(0x7fffff)));
+    assert_eq!(Word::try_from(-0x800001), Err(OutOfRange(-0x800001)));
+    assert_eq!(Word::try_from(0x800000), Err(OutOfRange(0x800000)));
+}
+
 #[test]
 fn test_address_truncation() {
     let a: Word = 0x11223344.into();
@@ -58,4 +192,83 @@ fn test_address_overflows() {
     let mut d = Word::from(5);
     d -= 3;
     assert_eq!(d, Word(2));
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_index_masking() {
+    assert_eq!(usize::from(Word::from(0)), 0);
+    assert_eq!(usize::from(Word::from(MEM_SIZE - 1)), MEM_SIZE as usize - 1);
+    assert_eq!(usize::from(Word::from(MEM_SIZE)), 0);
+    assert_eq!(usize::from(Word::from(MEM_SIZE + 5)), 5);
+    assert_eq!(usize::from(Word::from(0xffffff)), MEM_SIZE as usize - 1);
+    assert_eq!(usize::from(Word::from(0x1000000)), 0); // Truncated to 24 bits first
+}
+
+#[test]
+fn test_offset() {
+    assert_eq!(Word::from(0x400).offset(35), Word(0x423));
+    assert_eq!(Word::from(0x400).offset(-0x400), Word(0));
+    assert_eq!(Word::from(0x400).offset(0), Word(0x400));
+    assert_eq!(Word::from(0xfffffe).offset(5), Word(3));
+    assert_eq!(Word::from(2).offset(-5), Word(0xfffffd));
+    assert_eq!(Word::from(0).offset(i32::MIN), Word(0)); // Whole multiple of 2^24
+    assert_eq!(Word::from(0x400).offset(Word::from(0xfffffd).as_signed()), Word(0x3fd));
+}
+
+#[test]
+fn test_changed_bits() {
+    assert_eq!(Word::from(0b101).changed_bits(0b110.into()), Word::from(0b011));
+    assert_eq!(Word::from(0xffffff).changed_bits(0.into()), Word::from(0xffffff));
+    assert_eq!(Word::from(1234).changed_bits(1234.into()), Word::from(0));
+}
+
+#[test]
+fn test_signed() {
+    assert_eq!(Word::from(0).as_signed(), 0);
+    assert_eq!(Word::from(0x7fffff).as_signed(), 0x7fffff);
+    assert_eq!(Word::from(0x800000).as_signed(), -0x800000);
+    assert_eq!(Word::from(0xffffff).as_signed(), -1);
+    assert_eq!(Word::from(0x800000).as_unsigned(), 0x800000);
+    assert!(!Word::from(0).is_negative());
+    assert!(!Word::from(0x7fffff).is_negative());
+    assert!(Word::from(0x800000).is_negative());
+    assert!(Word::from(0xffffff).is_negative());
+}
+
+#[test]
+fn test_hex() {
+    assert_eq!(format!("{:#08x}", Word::from(1234)), "0x0004d2");
+    assert_eq!(format!("{:06x}", Word::from(0xabc)), "000abc");
+    assert_eq!(format!("{:06X}", Word::from(0xabc)), "000ABC");
+    assert_eq!(format!("{:x}", Word::from(0)), "0");
+    assert_eq!(format!("{:#X}", Word::from(0xffffff)), "0xFFFFFF");
+}
+
+#[test]
+fn test_checked_arithmetic() {
+    let max = Word::from(0xffffff);
+    assert_eq!(Word::from(0xfffffe).checked_add(1.into()), Some(max));
+    assert_eq!(max.checked_add(1.into()), None);
+    assert_eq!(max.checked_add(max), None);
+
+    assert_eq!(max.checked_sub(max), Some(Word(0)));
+    assert_eq!(Word::from(0).checked_sub(1.into()), None);
+
+    assert_eq!(Word::from(0x555555).checked_mul(3.into()), Some(max));
+    assert_eq!(Word::from(0x800000).checked_mul(2.into()), None);
+    assert_eq!(max.checked_mul(max), None); // Overflows u32 too
+}
+
+#[test]
+fn test_saturating_arithmetic() {
+    let max = Word::from(0xffffff);
+    assert_eq!(Word::from(0xfffffe).saturating_add(1.into()), max);
+    assert_eq!(Word::from(0xfffffe).saturating_add(2.into()), max);
+    assert_eq!(max.saturating_add(max), max);
+    assert_eq!(Word::from(5).saturating_add(6.into()), Word(11));
+
+    assert_eq!(Word::from(1).saturating_sub(1.into()), Word(0));
+    assert_eq!(Word::from(1).saturating_sub(2.into()), Word(0));
+    assert_eq!(Word::from(0).saturating_sub(max), Word(0));
+    assert_eq!(Word::from(11).saturating_sub(6.into()), Word(5));
+}
@@ -0,0 +1,373 @@
+use crate::layout::ENTRY_POINT;
+use crate::opcodes::Opcode;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+/// One line of source, remembering where it came from so errors can point
+/// at the right file after includes have been spliced together.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SourceLine {
+    pub file: String,
+    pub number: usize, // 1-based, like an editor
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AssembleError {
+    FileNotFound(String),
+    CircularInclude(Vec<String>), // The chain of files, ending with the one that closes the loop
+    BadDirective(SourceLine),
+    UnknownMnemonic(SourceLine),
+    BadArgument(SourceLine),
+    DuplicateLabel(SourceLine),
+    UnknownLabel(SourceLine),
+}
+
+impl Display for AssembleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AssembleError::FileNotFound(name) => write!(f, "Can't find file \"{}\"", name),
+            AssembleError::CircularInclude(chain) => write!(f, "Circular include: {}", chain.join(" -> ")),
+            AssembleError::BadDirective(line) => write!(f, "{}:{}: bad directive \"{}\"", line.file, line.number, line.text.trim()),
+            AssembleError::UnknownMnemonic(line) => write!(f, "{}:{}: unknown instruction \"{}\"", line.file, line.number, line.text.trim()),
+            AssembleError::BadArgument(line) => write!(f, "{}:{}: bad argument \"{}\"", line.file, line.number, line.text.trim()),
+            AssembleError::DuplicateLabel(line) => write!(f, "{}:{}: label already defined \"{}\"", line.file, line.number, line.text.trim()),
+            AssembleError::UnknownLabel(line) => write!(f, "{}:{}: undefined label \"{}\"", line.file, line.number, line.text.trim()),
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// Assembles source text, one instruction per line, into machine code. An
+/// instruction is a mnemonic and optionally an argument, decimal or `0x` hex,
+/// and negative numbers are 24-bit two's complement; `push` is a synonym for
+/// `nop`, which with an argument just pushes it. Each argument is encoded in
+/// as few bytes as will hold it. Blank lines and `;` comments are skipped.
+///
+/// A line can start with a label, `name:`, which names the address of the
+/// instruction after it, and an argument can be a label instead of a number,
+/// defined before or after. Labels are absolute addresses for a program
+/// loaded at the entry point, or wherever an `.org address` line before any
+/// labels or instructions says it will be loaded instead. They always take
+/// three bytes, so an instruction's size doesn't depend on where the label
+/// turns out to be.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AssembleError> {
+    let lines: Vec<SourceLine> = source.lines().enumerate().map(|(n, text)| {
+        SourceLine { file: "<input>".to_string(), number: n + 1, text: text.to_string() }
+    }).collect();
+    assemble_lines(&lines)
+}
+
+/// Like `assemble`, but reading `file` and its includes through `resolve`;
+/// see `preprocess`.
+pub fn assemble_file<F>(file: &str, resolve: &mut F) -> Result<Vec<u8>, AssembleError>
+    where F: FnMut(&str) -> Option<String>
+{
+    assemble_lines(&preprocess(file, resolve)?)
+}
+
+// An instruction's argument, before labels are resolved
+enum Arg<'a> {
+    None,
+    Number(u32),
+    Label(&'a str),
+}
+
+impl Arg<'_> {
+    // How many bytes the argument takes up
+    fn length(&self) -> u8 {
+        match self {
+            Arg::None => 0,
+            Arg::Number(0..=0xff) => 1,
+            Arg::Number(0x100..=0xffff) => 2,
+            Arg::Number(_) | Arg::Label(_) => 3,
+        }
+    }
+}
+
+// Two passes: the first works out where every label is, which it can do
+// because every instruction's size is known without them, and the second
+// encodes the instructions with the labels filled in
+fn assemble_lines(lines: &[SourceLine]) -> Result<Vec<u8>, AssembleError> {
+    let mut instructions = Vec::new();
+    let mut labels = HashMap::new();
+    let mut addr = ENTRY_POINT;
+    for line in lines {
+        let mut code = line.text.split(';').next().unwrap().trim();
+        if code.starts_with('.') {
+            let started = !instructions.is_empty() || !labels.is_empty();
+            match directive(code, ".org").and_then(parse_number) {
+                Some(origin) if !started => addr = origin,
+                _ => return Err(AssembleError::BadDirective(line.clone()))
+            }
+            continue
+        }
+        if let Some((label, rest)) = code.split_once(':') {
+            if !is_label(label) {
+                return Err(AssembleError::BadDirective(line.clone()))
+            }
+            if labels.insert(label, addr).is_some() {
+                return Err(AssembleError::DuplicateLabel(line.clone()))
+            }
+            code = rest.trim()
+        }
+        if code.is_empty() {
+            continue
+        }
+
+        let mut tokens = code.split_whitespace();
+        let mnemonic = tokens.next().unwrap();
+        let opcode = match mnemonic {
+            "push" => Opcode::Nop,
+            other => Opcode::from_mnemonic(other).ok_or_else(|| AssembleError::UnknownMnemonic(line.clone()))?
+        };
+        let arg = match tokens.next() {
+            None => Arg::None,
+            Some(token) if is_label(token) => Arg::Label(token),
+            Some(token) => Arg::Number(parse_number(token).ok_or_else(|| AssembleError::BadArgument(line.clone()))?),
+        };
+        if tokens.next().is_some() {
+            return Err(AssembleError::BadArgument(line.clone()))
+        }
+        addr += 1 + arg.length() as u32;
+        instructions.push((line, opcode, arg));
+    }
+
+    let mut bytes = Vec::new();
+    for (line, opcode, arg) in instructions {
+        let length = arg.length();
+        let value = match arg {
+            Arg::None => 0,
+            Arg::Number(n) => n,
+            Arg::Label(label) => *labels.get(label).ok_or_else(|| AssembleError::UnknownLabel(line.clone()))?,
+        };
+        bytes.push(opcode.to_u8() << 2 | length);
+        bytes.extend_from_slice(&value.to_le_bytes()[..length as usize])
+    }
+    Ok(bytes)
+}
+
+// A label is a letter or underscore, then letters, digits and underscores
+fn is_label(token: &str) -> bool {
+    let mut chars = token.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// The rest of `code`, trimmed, if it's the directive `name`: the name, then
+// whitespace or nothing, so `.includes` isn't `.include`
+fn directive<'a>(code: &'a str, name: &str) -> Option<&'a str> {
+    let rest = code.strip_prefix(name)?;
+    if rest.is_empty() || rest.starts_with(char::is_whitespace) {
+        Some(rest.trim())
+    } else {
+        None
+    }
+}
+
+// A 24-bit word in decimal or 0x hex, optionally negative
+fn parse_number(token: &str) -> Option<u32> {
+    let (negative, digits) = match token.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, token)
+    };
+    let n = match digits.strip_prefix("0x") {
+        // Rust's parsers take a leading +, which numbers here can't have
+        Some(hex) if !hex.starts_with('+') => u32::from_str_radix(hex, 16).ok()?,
+        None if !digits.starts_with('+') => digits.parse().ok()?,
+        _ => return None
+    };
+    match (negative, n) {
+        (false, 0..=0xffffff) => Some(n),
+        (true, 0..=0x800000) => Some(0x1000000u32.wrapping_sub(n) & 0xffffff),
+        _ => None
+    }
+}
+
+/// Reads `file` through `resolve` and splices in the lines of any files it
+/// pulls in with `.include "name"`, recursively. Everything ends up in one
+/// flat list of lines, so labels defined in one file are visible from all
+/// the others. The resolver maps a file name to its contents, or `None` if
+/// there's no such file; that keeps this usable without a filesystem.
+pub fn preprocess<F>(file: &str, resolve: &mut F) -> Result<Vec<SourceLine>, AssembleError>
+    where F: FnMut(&str) -> Option<String>
+{
+    let mut lines = Vec::new();
+    let mut stack = Vec::new();
+    splice(file, resolve, &mut stack, &mut lines)?;
+    Ok(lines)
+}
+
+fn splice<F>(file: &str, resolve: &mut F, stack: &mut Vec<String>, lines: &mut Vec<SourceLine>) -> Result<(), AssembleError>
+    where F: FnMut(&str) -> Option<String>
+{
+    if stack.iter().any(|f| f == file) {
+        let mut chain = stack.clone();
+        chain.push(file.to_string());
+        return Err(AssembleError::CircularInclude(chain))
+    }
+
+    let source = resolve(file).ok_or_else(|| AssembleError::FileNotFound(file.to_string()))?;
+    stack.push(file.to_string());
+
+    for (n, text) in source.lines().enumerate() {
+        let line = SourceLine { file: file.to_string(), number: n + 1, text: text.to_string() };
+        match include_target(text) {
+            None => lines.push(line),
+            Some(Some(target)) => splice(target, resolve, stack, lines)?,
+            Some(None) => return Err(AssembleError::BadDirective(line)),
+        }
+    }
+
+    stack.pop();
+    Ok(())
+}
+
+// None if this isn't an include at all, Some(None) if it's a malformed one
+fn include_target(text: &str) -> Option<Option<&str>> {
+    let code = text.split(';').next().unwrap().trim();
+    let rest = directive(code, ".include")?;
+    Some(rest.strip_prefix('"').and_then(|r| r.strip_suffix('"')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{decode, Instruction};
+    use crate::memory::Memory;
+    use std::collections::HashMap;
+
+    fn resolver(files: &[(&str, &str)]) -> impl FnMut(&str) -> Option<String> {
+        let files: HashMap<String, String> = files.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| files.get(name).cloned()
+    }
+
+    fn texts(lines: &[SourceLine]) -> Vec<&str> {
+        lines.iter().map(|l| l.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_include() {
+        let mut files = resolver(&[
+            ("main.asm", "push loop\n.include \"lib.asm\" ; the library\njmp"),
+            ("lib.asm", "loop:\nhlt"),
+        ]);
+        let lines = preprocess("main.asm", &mut files).unwrap();
+        assert_eq!(texts(&lines), vec!["push loop", "loop:", "hlt", "jmp"]);
+        assert_eq!(lines[1], SourceLine { file: "lib.asm".to_string(), number: 1, text: "loop:".to_string() });
+        assert_eq!(lines[3].number, 3);
+    }
+
+    #[test]
+    fn test_circular_include() {
+        let mut files = resolver(&[
+            ("a.asm", ".include \"b.asm\""),
+            ("b.asm", "nop\n.include \"a.asm\""),
+        ]);
+        assert_eq!(preprocess("a.asm", &mut files),
+                   Err(AssembleError::CircularInclude(vec!["a.asm".to_string(), "b.asm".to_string(), "a.asm".to_string()])));
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let mut files = resolver(&[("a.asm", ".include \"nope.asm\""), ("b.asm", ".include nope.asm")]);
+        assert_eq!(preprocess("a.asm", &mut files), Err(AssembleError::FileNotFound("nope.asm".to_string())));
+        assert!(matches!(preprocess("b.asm", &mut files), Err(AssembleError::BadDirective(_))));
+
+        // Including the same file twice is fine as long as it isn't a loop
+        let mut files = resolver(&[("a.asm", ".include \"b.asm\"\n.include \"b.asm\""), ("b.asm", "nop")]);
+        assert_eq!(texts(&preprocess("a.asm", &mut files).unwrap()), vec!["nop", "nop"]);
+    }
+
+    #[test]
+    fn test_assemble() {
+        let source = "push 2\n\n  add 0x123456 ; a comment\nsub 300\n; just a comment\npush -1\npush 0\nhlt";
+        let bytes = assemble(source).unwrap();
+        assert_eq!(bytes, vec![0x01, 0x02, 0x07, 0x56, 0x34, 0x12, 0x0a, 0x2c, 0x01, 0x03, 0xff, 0xff, 0xff, 0x01, 0x00, 29 << 2]);
+
+        let mut mem = Memory::default();
+        mem.load(0x400.into(), &bytes);
+        let expected = [
+            (0x400, Opcode::Nop, Some(2), 2),
+            (0x402, Opcode::Add, Some(0x123456), 4),
+            (0x406, Opcode::Sub, Some(300), 3),
+            (0x409, Opcode::Nop, Some(0xffffff), 4),
+            (0x40d, Opcode::Nop, Some(0), 2),
+            (0x40f, Opcode::Hlt, None, 1),
+        ];
+        for (addr, opcode, arg, length) in expected {
+            assert_eq!(decode(&mem, addr.into()), Ok(Instruction { opcode, arg, length }));
+        }
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let error_line = |source: &str| match assemble(source) {
+            Err(AssembleError::UnknownMnemonic(line)) | Err(AssembleError::BadArgument(line)) |
+                Err(AssembleError::DuplicateLabel(line)) | Err(AssembleError::UnknownLabel(line)) => line.number,
+            other => panic!("expected an error, got {:?}", other)
+        };
+        assert_eq!(error_line("nop\nfrob"), 2);
+        assert_eq!(error_line("push 0x1000000"), 1);
+        assert_eq!(error_line("push -0x800001"), 1);
+        assert_eq!(error_line("push 5five"), 1);
+        assert_eq!(error_line("push +5"), 1);
+        assert_eq!(error_line("push 0x+5"), 1);
+        assert_eq!(error_line("push -+5"), 1);
+        assert_eq!(error_line("push 1 2"), 1);
+        assert_eq!(error_line("push five"), 1); // Never defined
+        assert_eq!(error_line("a: nop\nb: nop\na: hlt"), 3);
+        assert!(matches!(assemble("two words: nop"), Err(AssembleError::BadDirective(_))));
+        assert!(matches!(assemble(".include \"x.asm\""), Err(AssembleError::BadDirective(_))));
+        assert!(matches!(assemble(".org"), Err(AssembleError::BadDirective(_))));
+        assert!(matches!(assemble(".org0x2000"), Err(AssembleError::BadDirective(_))));
+        assert!(matches!(assemble("nop\n.org 0x2000"), Err(AssembleError::BadDirective(_)))); // Too late
+        assert!(matches!(assemble("a:\n.org 0x2000"), Err(AssembleError::BadDirective(_))));
+    }
+
+    #[test]
+    fn test_directive_names() {
+        // Only the whole name counts, so this isn't an include, and assembling it fails
+        let mut files = resolver(&[("a.asm", ".includeX \"b.asm\""), ("b.asm", "nop")]);
+        assert_eq!(texts(&preprocess("a.asm", &mut files).unwrap()), vec![".includeX \"b.asm\""]);
+        assert!(matches!(assemble_file("a.asm", &mut files), Err(AssembleError::BadDirective(_))));
+        let mut files = resolver(&[("a.asm", ".include\t\"b.asm\""), ("b.asm", "nop")]);
+        assert_eq!(assemble_file("a.asm", &mut files), Ok(vec![0]));
+    }
+
+    #[test]
+    fn test_org() {
+        let source = "; loaded at 0x2000\n.org 0x2000\nstart: push 1\npush start\njmp";
+        assert_eq!(assemble(source).unwrap(), vec![0x01, 0x01, 0x03, 0x00, 0x20, 0x00, 23 << 2]);
+
+        // Running it there loops back to the start, pushing another 1 each time
+        let mut cpu = crate::cpu::CPU::new(Memory::default());
+        cpu.load_program(0x2000.into(), &assemble(source).unwrap());
+        assert_eq!(cpu.run(6), (6, crate::cpu::StopReason::CyclesExhausted));
+        assert_eq!(cpu.data_stack(), vec![1.into(), 1.into()]);
+    }
+
+    #[test]
+    fn test_labels() {
+        // Forward and backward, alone on a line or before an instruction
+        let source = "start: push end\njmp\n  loop:\nnop\nend: push start ; back again";
+        let bytes = assemble(source).unwrap();
+        assert_eq!(bytes, vec![0x03, 0x06, 0x04, 0x00, 23 << 2, 0, 0x03, 0x00, 0x04, 0x00]);
+
+        let mut files = resolver(&[
+            ("main.asm", "push done\njmp\n.include \"lib.asm\""),
+            ("lib.asm", "push 1\ndone: hlt"),
+        ]);
+        let mut cpu = crate::cpu::CPU::boot(&assemble_file("main.asm", &mut files).unwrap()).unwrap();
+        cpu.run(100);
+        assert_eq!(cpu.state(), crate::cpu::State::Halted);
+        assert_eq!(cpu.data_stack(), vec![]); // Jumped over the push to the label in lib.asm
+    }
+
+    #[test]
+    fn test_assemble_file() {
+        let mut files = resolver(&[("main.asm", "push 5\n.include \"lib.asm\""), ("lib.asm", "hlt")]);
+        assert_eq!(assemble_file("main.asm", &mut files), Ok(vec![0x01, 0x05, 29 << 2]));
+    }
+}
@@ -0,0 +1,165 @@
+use crate::address::Word;
+use crate::bus::Device;
+use crate::memory::PeekPoke;
+
+// Register layout, relative to wherever the audio device is mapped:
+//   0-2: frequency in Hz, a little-endian word
+//   3:   nonzero to play, 0 for silence
+// The tone is a square wave. Samples come out of `pull_samples`, so any
+// backend (or a test) can drain them at whatever rate it's running at.
+
+// Square waves are loud; keep some headroom
+const AMPLITUDE: f32 = 0.25;
+
+pub struct Audio {
+    frequency: u32,
+    enabled: bool,
+    sample_rate: u32,
+    phase: u32, // How far through the current cycle, in 1/sample_rate steps of frequency
+}
+
+impl Default for Audio {
+    fn default() -> Self { Self::new(44100) }
+}
+
+impl Audio {
+    pub fn new(sample_rate: u32) -> Self {
+        Self { frequency: 0, enabled: false, sample_rate, phase: 0 }
+    }
+
+    pub fn frequency(&self) -> u32 { self.frequency }
+    pub fn is_enabled(&self) -> bool { self.enabled }
+    pub fn sample_rate(&self) -> u32 { self.sample_rate }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.phase = 0
+    }
+
+    /// Fills `buf` with the next mono samples of the tone, or silence when
+    /// it's off. Frequencies above half the sample rate can't be
+    /// represented and come out silent too.
+    pub fn pull_samples(&mut self, buf: &mut [f32]) {
+        for sample in buf {
+            *sample = self.next_sample()
+        }
+    }
+
+    fn next_sample(&mut self) -> f32 {
+        if !self.enabled || self.frequency == 0 || self.frequency * 2 > self.sample_rate {
+            return 0.0
+        }
+        let high = self.phase < self.sample_rate / 2;
+        self.phase = (self.phase + self.frequency) % self.sample_rate;
+        if high { AMPLITUDE } else { -AMPLITUDE }
+    }
+}
+
+impl PeekPoke for Audio {
+    fn peek(&self, addr: Word) -> u8 {
+        match u32::from(addr) {
+            n @ 0..=2 => (self.frequency >> (8 * n)) as u8,
+            3 => self.enabled as u8,
+            _ => 0
+        }
+    }
+
+    fn poke(&mut self, addr: Word, val: u8) {
+        match u32::from(addr) {
+            n @ 0..=2 => {
+                self.frequency = self.frequency & !(0xff << (8 * n)) | (val as u32) << (8 * n)
+            }
+            3 => self.enabled = val != 0,
+            _ => {}
+        }
+    }
+}
+
+impl Device for Audio {
+    fn tick(&mut self) {}
+
+    fn reset(&mut self) {
+        *self = Self::new(self.sample_rate)
+    }
+}
+
+/// Plays `audio` on the default output device until the returned stream is
+/// dropped. The device has to be shared with the audio thread, so the CPU
+/// pokes it through the same mutex.
+#[cfg(feature = "cpal")]
+pub fn play(audio: std::sync::Arc<std::sync::Mutex<Audio>>) -> Result<cpal::Stream, Box<dyn std::error::Error>> {
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+    let device = cpal::default_host().default_output_device().ok_or("No audio output device")?;
+    let config: cpal::StreamConfig = device.default_output_config()?.into();
+    let channels = config.channels as usize;
+    audio.lock().unwrap().set_sample_rate(config.sample_rate.0);
+
+    let stream = device.build_output_stream(
+        &config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            let mut audio = audio.lock().unwrap();
+            for frame in data.chunks_mut(channels) {
+                frame.fill(audio.next_sample())
+            }
+        },
+        |e| log::warn!("Audio output failed: {}", e))?;
+    stream.play()?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_square_wave() {
+        let mut audio = Audio::new(8000);
+        audio.poke24_u32(0, 1000);
+        audio.poke_u32(3, 1);
+        assert_eq!(audio.peek24_u32(0), 1000);
+
+        // 8 samples per cycle: 4 high, 4 low, over and over
+        let mut buf = [0.0; 32];
+        audio.pull_samples(&mut buf);
+        for (i, &sample) in buf.iter().enumerate() {
+            let expected = if i % 8 < 4 { AMPLITUDE } else { -AMPLITUDE };
+            assert_eq!(sample, expected, "sample {}", i);
+        }
+
+        // Carries on from where it left off
+        let mut more = [0.0; 8];
+        audio.pull_samples(&mut more);
+        assert_eq!(&more, &buf[..8]);
+    }
+
+    #[test]
+    fn test_period() {
+        let mut audio = Audio::new(44100);
+        audio.poke24_u32(0, 441); // 100 samples per cycle
+        audio.poke_u32(3, 1);
+        let mut buf = vec![0.0; 1000];
+        audio.pull_samples(&mut buf);
+        let rising = buf.windows(2).filter(|w| w[0] < 0.0 && w[1] > 0.0).count();
+        assert_eq!(rising, 9); // Ten cycles, and the first starts high
+    }
+
+    #[test]
+    fn test_silence() {
+        let mut audio = Audio::new(8000);
+        let mut buf = [1.0; 16];
+        audio.pull_samples(&mut buf);
+        assert!(buf.iter().all(|&s| s == 0.0));
+
+        audio.poke24_u32(0, 5000); // Above the Nyquist frequency
+        audio.poke_u32(3, 1);
+        audio.pull_samples(&mut buf);
+        assert!(buf.iter().all(|&s| s == 0.0));
+
+        audio.poke24_u32(0, 1000);
+        audio.reset();
+        assert!(!audio.is_enabled());
+        assert_eq!(audio.frequency(), 0);
+        assert_eq!(audio.sample_rate(), 8000);
+    }
+}
@@ -0,0 +1,217 @@
+// A machine monitor: load a ROM and poke at it from the keyboard, a step at
+// a time, without a window. Type `help` for the commands.
+
+use std::io::{BufRead, Write};
+use vulcan_emu::address::Word;
+use vulcan_emu::cpu::{StopReason, CPU};
+use vulcan_emu::disassembler::{disassemble, disassemble_one};
+use vulcan_emu::display;
+use vulcan_emu::layout::ENTRY_POINT;
+use vulcan_emu::memory::{Memory, PeekPoke};
+
+const HELP: &str = "\
+step              execute one instruction
+run N             run up to N instructions
+reg               show the registers and stacks
+dump ADDR LEN     show LEN bytes of memory from ADDR
+poke ADDR VAL     store the byte VAL at ADDR
+dis ADDR N        disassemble N instructions from ADDR
+bp ADDR           stop runs at ADDR
+quit
+Numbers are decimal, or hex with 0x.";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Command {
+    Step,
+    Run(usize),
+    Reg,
+    Dump(Word, usize),
+    Poke(Word, u8),
+    Dis(Word, usize),
+    Bp(Word),
+    Help,
+    Quit,
+}
+
+fn parse(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let arg = |n: usize| -> Result<u32, String> {
+        let word = words.get(n).ok_or_else(|| format!("{} needs more arguments", words[0]))?;
+        parse_number(word).ok_or_else(|| format!("Bad number \"{}\"", word))
+    };
+    let args = |n: usize| if words.len() == n + 1 { Ok(()) } else { Err(format!("{} takes {} arguments", words[0], n)) };
+
+    match words.first().copied() {
+        Some("step") => args(0).map(|_| Command::Step),
+        Some("run") => { args(1)?; Ok(Command::Run(arg(1)? as usize)) }
+        Some("reg") => args(0).map(|_| Command::Reg),
+        Some("dump") => { args(2)?; Ok(Command::Dump(arg(1)?.into(), arg(2)? as usize)) }
+        Some("poke") => {
+            args(2)?;
+            let val = u8::try_from(arg(2)?).map_err(|_| "Value doesn't fit in a byte".to_string())?;
+            Ok(Command::Poke(arg(1)?.into(), val))
+        }
+        Some("dis") => { args(2)?; Ok(Command::Dis(arg(1)?.into(), arg(2)? as usize)) }
+        Some("bp") => { args(1)?; Ok(Command::Bp(arg(1)?.into())) }
+        Some("help") => Ok(Command::Help),
+        Some("quit") => Ok(Command::Quit),
+        Some(other) => Err(format!("Unknown command \"{}\", try help", other)),
+        None => Err("Type a command, or help".to_string())
+    }
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok()
+    }
+}
+
+// Carries out a command, returning false once it's time to stop
+fn execute(cpu: &mut CPU, command: Command, out: &mut dyn Write) -> std::io::Result<bool> {
+    match command {
+        Command::Step => {
+            match cpu.step() {
+                Ok(state) => writeln!(out, "{:?}", state)?,
+                Err(e) => writeln!(out, "{}", e)?
+            }
+            show_next(cpu, out)?
+        }
+        Command::Run(n) => {
+            match cpu.run(n) {
+                (ran, StopReason::Fault(e)) => writeln!(out, "Ran {} instructions: {}", ran, e)?,
+                (ran, stop) => writeln!(out, "Ran {} instructions: {:?}", ran, stop)?
+            }
+            show_next(cpu, out)?
+        }
+        Command::Reg => {
+            let regs = cpu.registers();
+            writeln!(out, "pc {:06x} dp {:06x} sp {:06x} iv {:06x} int {} halted {} waiting {}",
+                     regs.pc, regs.dp, regs.sp, regs.iv, regs.int_enabled, regs.halted, regs.waiting)?;
+            let hex = |stack: Vec<Word>| stack.iter().map(|w| format!("{:06x}", w)).collect::<Vec<_>>().join(" ");
+            writeln!(out, "data [{}]", hex(cpu.data_stack()))?;
+            writeln!(out, "call [{}]", hex(cpu.call_stack()))?
+        }
+        Command::Dump(addr, len) => {
+            for row in (0..len).step_by(16) {
+                let bytes: Vec<String> = (row..len.min(row + 16))
+                    .map(|i| format!("{:02x}", cpu.memory().peek(addr + i as i32)))
+                    .collect();
+                writeln!(out, "{:06x}: {}", addr + row as i32, bytes.join(" "))?
+            }
+        }
+        Command::Poke(addr, val) => cpu.memory_mut().poke(addr, val),
+        Command::Dis(addr, n) => {
+            for (addr, text) in disassemble(cpu.memory(), addr, n) {
+                writeln!(out, "{:06x}: {}", addr, text)?
+            }
+        }
+        Command::Bp(addr) => cpu.add_breakpoint(addr),
+        Command::Help => writeln!(out, "{}", HELP)?,
+        Command::Quit => return Ok(false)
+    }
+    Ok(true)
+}
+
+fn show_next(cpu: &CPU, out: &mut dyn Write) -> std::io::Result<()> {
+    let (text, _, _) = disassemble_one(cpu.memory(), cpu.pc());
+    writeln!(out, "next {:06x}: {}", cpu.pc(), text)
+}
+
+// Loads the ROM at the entry point, or says why it didn't fit
+fn load_rom(cpu: &mut CPU, rom: &[u8]) -> Result<(), String> {
+    if cpu.load_program(ENTRY_POINT.into(), rom) < rom.len() {
+        return Err(format!("{} bytes, too big to fit in memory at {}", rom.len(), ENTRY_POINT))
+    }
+    Ok(())
+}
+
+fn main() -> std::io::Result<()> {
+    env_logger::init();
+    let mut memory = Memory::default();
+    display::reset(&mut memory);
+    let mut cpu = CPU::new(memory);
+
+    if let Some(path) = std::env::args().nth(1) {
+        let rom = std::fs::read(&path).unwrap_or_else(|e| {
+            eprintln!("Can't read {}: {}", path, e);
+            std::process::exit(1)
+        });
+        if let Err(e) = load_rom(&mut cpu, &rom) {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1)
+        }
+    }
+
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    loop {
+        write!(stdout, "> ")?;
+        stdout.flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(())
+        }
+        match parse(&line) {
+            Ok(command) => {
+                if !execute(&mut cpu, command, &mut stdout)? {
+                    return Ok(())
+                }
+            }
+            Err(e) => writeln!(stdout, "{}", e)?
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("step"), Ok(Command::Step));
+        assert_eq!(parse("  run 100\n"), Ok(Command::Run(100)));
+        assert_eq!(parse("reg"), Ok(Command::Reg));
+        assert_eq!(parse("dump 0x400 32"), Ok(Command::Dump(0x400.into(), 32)));
+        assert_eq!(parse("poke 1024 0xff"), Ok(Command::Poke(1024.into(), 0xff)));
+        assert_eq!(parse("dis 0x400 5"), Ok(Command::Dis(0x400.into(), 5)));
+        assert_eq!(parse("bp 0x404"), Ok(Command::Bp(0x404.into())));
+        assert_eq!(parse("quit"), Ok(Command::Quit));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("jump 5").is_err());
+        assert!(parse("run").is_err());
+        assert!(parse("run 5 6").is_err());
+        assert!(parse("run five").is_err());
+        assert!(parse("poke 0x400 256").is_err());
+        assert!(parse("step 1").is_err());
+    }
+
+    #[test]
+    fn test_execute() {
+        let mut cpu = CPU::new(Memory::default());
+        cpu.load_program(1024.into(), &[0x01, 0x05, 29 << 2]); // push 5, hlt
+        let mut out = Vec::new();
+        assert!(execute(&mut cpu, Command::Poke(0x10.into(), 0xab), &mut out).unwrap());
+        execute(&mut cpu, Command::Run(10), &mut out).unwrap();
+        execute(&mut cpu, Command::Reg, &mut out).unwrap();
+        execute(&mut cpu, Command::Dump(0x10.into(), 2), &mut out).unwrap();
+        assert!(!execute(&mut cpu, Command::Quit, &mut out).unwrap());
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Ran 2 instructions: Halted"), "{}", out);
+        assert!(out.contains("data [000005]"), "{}", out);
+        assert!(out.contains("000010: ab 00"), "{}", out);
+    }
+
+    #[test]
+    fn test_load_rom() {
+        let mut cpu = CPU::new(Memory::default());
+        let room = 0x20000 - ENTRY_POINT as usize;
+        assert_eq!(load_rom(&mut cpu, &vec![0; room]), Ok(()));
+        assert_eq!(load_rom(&mut cpu, &vec![0; room + 1]), Err("130049 bytes, too big to fit in memory at 1024".to_string()));
+    }
+}
@@ -1,36 +1,112 @@
 use crate::address::Word;
 use crate::memory::PeekPoke;
-use std::ops::Range;
+use std::cell::RefCell;
+use std::io::Write;
+use std::ops::RangeInclusive;
 
 pub trait Device {
     fn tick(&mut self);
     fn reset(&mut self);
+
+    /// Whether the device wants to interrupt the CPU. Asking clears the
+    /// request, so each one is only delivered once.
+    fn take_interrupt(&mut self) -> bool { false }
 }
 
+/// Anything that can be mapped into the address space and also advances
+/// over time, like a timer or an input device
+pub trait MappedDevice: PeekPoke + Device {}
+
+impl<T: PeekPoke + Device> MappedDevice for T {}
+
+/// Maps `device` into the addresses from `start` to `end` inclusive, and
+/// sends every other address on to `rest`, which can be another `Bus`. The
+/// device sees its addresses relative to `start`, so it doesn't need to know
+/// where it's mapped.
+///
+/// A 24-bit access that lies wholly inside the device or wholly outside it
+/// goes to that side as one access. One that straddles `start` or `end` is
+/// split a byte at a time, each byte going to whichever side owns its
+/// address, the same as three single-byte accesses would.
+///
+/// ```
+/// use vulcan_emu::bus::map;
+/// use vulcan_emu::keyboard::Keyboard;
+/// use vulcan_emu::memory::{Memory, PeekPoke};
+/// use vulcan_emu::rom::Rom;
+///
+/// let mut bus = map(0x400..=0x4ff, Rom::new(vec![0x74; 256]))
+///     .and(map(0x80..=0x83, Keyboard::default())
+///     .and(Memory::default()));
+///
+/// bus.poke_u32(0x400, 0); // The ROM ignores writes
+/// assert_eq!(bus.peek_u32(0x400), 0x74);
+/// assert_eq!(bus.peek_u32(0x81), 0); // No key events queued
+/// bus.poke_u32(0x500, 9); // Everything else is memory
+/// assert_eq!(bus.peek_u32(0x500), 9);
+/// ```
 pub struct Bus<A, B> {
-    range: Range<Word>,
+    range: RangeInclusive<Word>,
     device: A,
     rest: B,
 }
 
 impl<A, B> Bus<A, B> {
-    fn new(start: u32, end: u32, device: A, rest: B) -> Self {
+    pub fn new(start: u32, end: u32, device: A, rest: B) -> Self {
         Self {
-            range: start.into()..end.into(),
+            range: start.into()..=end.into(),
             device,
             rest,
         }
     }
 
-    fn at(addr: u32, device: A, rest: B) -> Self {
+    /// A device that takes up just the one address
+    pub fn at(addr: u32, device: A, rest: B) -> Self {
         Self::new(addr, addr, device, rest)
     }
+
+    pub fn device(&self) -> &A { &self.device }
+
+    pub fn device_mut(&mut self) -> &mut A { &mut self.device }
+
+    /// Whatever handles the addresses outside the device
+    pub fn rest(&self) -> &B { &self.rest }
+
+    pub fn rest_mut(&mut self) -> &mut B { &mut self.rest }
+
+    // Some(true) if the word at `addr` is all in the device, Some(false) if
+    // it's all in the rest, and None if it's split between them
+    fn word_in_device(&self, addr: Word) -> Option<bool> {
+        let inside = |offset: i32| self.range.contains(&(addr + offset));
+        match (inside(0), inside(1), inside(2)) {
+            (true, true, true) => Some(true),
+            (false, false, false) => Some(false),
+            _ => None
+        }
+    }
+}
+
+/// Half of a `Bus`: a device and where it goes, waiting for `and` to say
+/// what handles the rest of the addresses
+pub struct Mapping<A> {
+    range: RangeInclusive<u32>,
+    device: A,
+}
+
+pub fn map<A>(range: RangeInclusive<u32>, device: A) -> Mapping<A> {
+    Mapping { range, device }
+}
+
+impl<A> Mapping<A> {
+    pub fn and<B>(self, rest: B) -> Bus<A, B> {
+        Bus::new(*self.range.start(), *self.range.end(), self.device, rest)
+    }
 }
 
 impl<A: PeekPoke, B: PeekPoke> PeekPoke for Bus<A, B> {
     fn peek(&self, addr: Word) -> u8 {
         if self.range.contains(&addr) {
-            self.device.peek(addr - self.range.start)
+            self.device.peek(addr - *self.range.start())
         } else {
             self.rest.peek(addr)
         }
@@ -38,11 +114,31 @@ impl<A: PeekPoke, B: PeekPoke> PeekPoke for Bus<A, B> {
 
     fn poke(&mut self, addr: Word, val: u8) {
         if self.range.contains(&addr) {
-            self.device.poke(addr - self.range.start, val)
+            self.device.poke(addr - *self.range.start(), val)
         } else {
             self.rest.poke(addr, val)
         }
     }
+
+    fn peek24(&self, addr: Word) -> u32 {
+        match self.word_in_device(addr) {
+            Some(true) => self.device.peek24(addr - *self.range.start()),
+            Some(false) => self.rest.peek24(addr),
+            None => (0..3).map(|i| (self.peek(addr + i) as u32) << (8 * i)).sum()
+        }
+    }
+
+    fn poke24(&mut self, addr: Word, val: u32) {
+        match self.word_in_device(addr) {
+            Some(true) => self.device.poke24(addr - *self.range.start(), val),
+            Some(false) => self.rest.poke24(addr, val),
+            None => {
+                for i in 0..3 {
+                    self.poke(addr + i, (val >> (8 * i)) as u8)
+                }
+            }
+        }
+    }
 }
 
 impl<A: Device, B: Device> Device for Bus<A, B> {
@@ -55,11 +151,87 @@ impl<A: Device, B: Device> Device for Bus<A, B> {
         self.device.reset();
         self.rest.reset();
     }
+
+    // Requests from several devices at once collapse into one interrupt
+    fn take_interrupt(&mut self) -> bool {
+        let device = self.device.take_interrupt();
+        let rest = self.rest.take_interrupt();
+        device || rest
+    }
+}
+
+/// Wraps a device, or a whole `Bus`, and writes a line to `out` for every
+/// access that goes through it, like a logic analyzer on the bus. 16 and
+/// 24-bit accesses, like the CPU's `loadw` and `storew`, are logged as one
+/// line each rather than a line per byte. Wrap only the part you're
+/// interested in; everything else goes untraced, at full speed.
+pub struct Tracing<D, W> {
+    inner: D,
+    out: RefCell<W>, // Peeks only get &self, but still need to write
+}
+
+impl<D, W: Write> Tracing<D, W> {
+    pub fn new(inner: D, out: W) -> Self {
+        Self { inner, out: RefCell::new(out) }
+    }
+
+    pub fn inner(&self) -> &D { &self.inner }
+
+    pub fn into_parts(self) -> (D, W) { (self.inner, self.out.into_inner()) }
+
+    // A trace that can't be written shouldn't stop the machine, so errors are dropped
+    fn log(&self, args: std::fmt::Arguments) {
+        let _ = writeln!(self.out.borrow_mut(), "{}", args);
+    }
+}
+
+impl<D: PeekPoke, W: Write> PeekPoke for Tracing<D, W> {
+    fn peek(&self, addr: Word) -> u8 {
+        let val = self.inner.peek(addr);
+        self.log(format_args!("peek {:06x} = {:02x}", addr, val));
+        val
+    }
+
+    fn poke(&mut self, addr: Word, val: u8) {
+        self.log(format_args!("poke {:06x} <- {:02x}", addr, val));
+        self.inner.poke(addr, val)
+    }
+
+    fn peek16(&self, addr: Word) -> u16 {
+        let val = self.inner.peek16(addr);
+        self.log(format_args!("peek16 {:06x} = {:04x}", addr, val));
+        val
+    }
+
+    fn poke16(&mut self, addr: Word, val: u16) {
+        self.log(format_args!("poke16 {:06x} <- {:04x}", addr, val));
+        self.inner.poke16(addr, val)
+    }
+
+    fn peek24(&self, addr: Word) -> u32 {
+        let val = self.inner.peek24(addr);
+        self.log(format_args!("peek24 {:06x} = {:06x}", addr, val));
+        val
+    }
+
+    fn poke24(&mut self, addr: Word, val: u32) {
+        self.log(format_args!("poke24 {:06x} <- {:06x}", addr, val));
+        self.inner.poke24(addr, val)
+    }
+}
+
+impl<D: Device, W> Device for Tracing<D, W> {
+    fn tick(&mut self) { self.inner.tick() }
+    fn reset(&mut self) { self.inner.reset() }
+    fn take_interrupt(&mut self) -> bool { self.inner.take_interrupt() }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cpu::{CPU, StopReason};
+    use crate::layout::ENTRY_POINT;
+    use crate::memory::Memory;
 
     struct TestDevice(i32);
     impl Device for TestDevice {
@@ -107,6 +279,22 @@ mod tests {
         assert_eq!(bus.rest.0, 10);
     }
 
+    struct Requester(bool);
+    impl Device for Requester {
+        fn tick(&mut self) {}
+        fn reset(&mut self) {}
+        fn take_interrupt(&mut self) -> bool { std::mem::take(&mut self.0) }
+    }
+
+    #[test]
+    fn test_take_interrupt() {
+        let mut bus = Bus::at(5, Requester(false), Bus::at(6, Requester(true), Requester(true)));
+        assert!(bus.take_interrupt());
+        assert!(!bus.rest.device.0);
+        assert!(!bus.rest.rest.0);
+        assert!(!bus.take_interrupt());
+    }
+
     #[test]
     fn test_poke_peek() {
         let mut bus = Bus::new(5, 10, ArrayDevice([0u8; 10]), ArrayDevice([0u8; 10]));
@@ -121,5 +309,72 @@ mod tests {
 
         assert_eq!(bus.peek_u32(2), 2); // Reading from the first device
         assert_eq!(bus.peek_u32(6), 6); // And the second
+
+        // Both ends are part of the range
+        bus.poke_u32(5, 5);
+        bus.poke_u32(10, 10);
+        bus.poke_u32(4, 4);
+        assert_eq!((bus.device.0[0], bus.device.0[5]), (5, 10));
+        assert_eq!(bus.rest.0[4], 4);
+    }
+
+    #[test]
+    fn test_map() {
+        let mut bus = map(5..=5, ArrayDevice([0u8; 10])).and(ArrayDevice([0u8; 10]));
+        bus.poke_u32(5, 1);
+        bus.poke_u32(6, 2);
+        assert_eq!(bus.device.0[0], 1);
+        assert_eq!(bus.rest.0[6], 2);
+    }
+
+    #[test]
+    fn test_tracing() {
+        let mut bus = map(5..=9, Tracing::new(ArrayDevice([0u8; 10]), Vec::new())).and(Memory::default());
+        bus.poke_u32(6, 0xab);
+        bus.poke_u32(0x100, 1); // Not traced
+        bus.poke24_u32(7, 0x123456);
+        assert_eq!(bus.peek_u32(6), 0xab);
+        assert_eq!(bus.peek24_u32(7), 0x123456);
+        bus.device.poke16(8.into(), 0xbeef); // Bus itself doesn't pass 16-bit accesses down whole
+        assert_eq!(bus.device.peek16(8.into()), 0xbeef);
+
+        let (device, out) = bus.device.into_parts();
+        assert_eq!(&device.0[1..5], &[0xab, 0x56, 0x34, 0x12]);
+        assert_eq!(&device.0[8..], &[0xef, 0xbe]);
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "poke 000001 <- ab\npoke24 000002 <- 123456\npeek 000001 = ab\npeek24 000002 = 123456\n\
+                    poke16 000008 <- beef\npeek16 000008 = beef\n");
+    }
+
+    #[test]
+    fn test_tracing_cpu_store() {
+        let program = crate::asm::assemble("push 0x123456\npush 0x2002\nstorew\nhlt").unwrap();
+        let mut memory = Memory::default();
+        memory.load(ENTRY_POINT.into(), &program);
+        let mut out = Vec::new();
+        let mut cpu = CPU::new(map(0x2000..=0x2009, Tracing::new(ArrayDevice([0u8; 10]), &mut out)).and(memory));
+        cpu.resume();
+        assert_eq!(cpu.run(100), (4, StopReason::Halted));
+        drop(cpu);
+        assert_eq!(String::from_utf8(out).unwrap(), "poke24 000002 <- 123456\n");
+    }
+
+    #[test]
+    fn test_straddling_word() {
+        let mut bus = Bus::new(5, 9, ArrayDevice([0u8; 10]), Memory::default());
+        bus.poke24_u32(9, 0x030201); // Starts on the device's last address
+        assert_eq!(bus.device.0[4], 0x01);
+        assert_eq!(&bus.device.0[5..], &[0; 5]); // The device sees nothing past its end...
+        assert_eq!(bus.rest.peek24_u32(9), 0x030200); // ...and the rest nothing before it
+        assert_eq!(bus.peek24_u32(9), 0x030201);
+
+        bus.poke24_u32(3, 0x060504); // Runs into the device's first address
+        assert_eq!(bus.rest.peek24_u32(3), 0x000504);
+        assert_eq!(bus.device.0[0], 0x06);
+        assert_eq!(bus.peek24_u32(3), 0x060504);
+
+        bus.poke24_u32(6, 0x090807); // Wholly inside
+        assert_eq!(&bus.device.0[1..4], &[0x07, 0x08, 0x09]);
+        assert_eq!(bus.rest.peek24_u32(6), 0);
     }
 }
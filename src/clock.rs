@@ -0,0 +1,123 @@
+use crate::cpu::{StopReason, CPU};
+use crate::memory::PeekPoke;
+use std::time::Duration;
+
+/// How fast the emulated machine runs. Cycles here are what
+/// `CPU::cycle_count` and the `cycles` instruction count: each instruction
+/// takes its `cycle_cost`, so loads and jumps take longer than adds.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ClockConfig {
+    pub cycles_per_second: u64,
+    pub frames_per_second: u64,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self { cycles_per_second: 1_000_000, frames_per_second: 60 }
+    }
+}
+
+impl ClockConfig {
+    /// How many cycles run between one vblank and the next
+    pub fn cycles_per_frame(&self) -> usize {
+        (self.cycles_per_second / self.frames_per_second.max(1)) as usize
+    }
+
+    /// How long a frame lasts in real time
+    pub fn frame_duration(&self) -> Duration {
+        Duration::from_secs(1) / self.frames_per_second.max(1) as u32
+    }
+}
+
+/// Keeps a CPU to the rate in a `ClockConfig`, however fast the host is.
+/// Time is passed in rather than read from the system clock, so a test can
+/// feed it simulated time.
+pub struct Limiter {
+    config: ClockConfig,
+    owed: u64, // Cycles the CPU has been given since it started
+    overrun: u64, // Cycles the CPU ran past what it was given, to take off the next run
+}
+
+impl Limiter {
+    pub fn new(config: ClockConfig) -> Self {
+        Self { config, owed: 0, overrun: 0 }
+    }
+
+    pub fn config(&self) -> ClockConfig { self.config }
+
+    /// How many cycles the CPU should run now to catch up to where it ought
+    /// to be `elapsed` after it started, and counts them as run. A host that
+    /// falls more than a frame behind doesn't get to make it all up at once:
+    /// it gets at most two frames' worth and the rest is dropped, so the
+    /// machine slows down instead of lurching.
+    pub fn cycles_due(&mut self, elapsed: Duration) -> usize {
+        let target = (elapsed.as_nanos() * self.config.cycles_per_second as u128 / 1_000_000_000) as u64;
+        let most = 2 * self.config.cycles_per_frame() as u64;
+        if target > self.owed + most {
+            self.owed = target - most
+        }
+        let due = target.saturating_sub(self.owed);
+        self.owed += due;
+        due as usize
+    }
+
+    /// Runs `cpu` for however many cycles are due at `elapsed`; see
+    /// `cycles_due`. An instruction that takes the CPU past what's due is
+    /// paid for out of the next run, so the rate comes out right over time.
+    pub fn run<M: PeekPoke>(&mut self, cpu: &mut CPU<M>, elapsed: Duration) -> (usize, StopReason) {
+        let due = self.cycles_due(elapsed) as u64;
+        let paid = due.min(self.overrun);
+        self.overrun -= paid;
+        let budget = due - paid;
+        let before = cpu.cycle_count();
+        let result = cpu.run_cycles(budget);
+        self.overrun += cpu.cycle_count().saturating_sub(before).saturating_sub(budget);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = ClockConfig { cycles_per_second: 600, frames_per_second: 60 };
+        assert_eq!(config.cycles_per_frame(), 10);
+        assert_eq!(config.frame_duration(), Duration::from_nanos(16_666_666));
+    }
+
+    #[test]
+    fn test_limiter() {
+        let mut limiter = Limiter::new(ClockConfig { cycles_per_second: 6000, frames_per_second: 60 });
+        let mut cpu = CPU::boot(&[]).unwrap(); // Nothing but nops
+        let mut total = 0;
+        for frame in 1..=60 {
+            let (n, stop) = limiter.run(&mut cpu, Duration::from_secs(1) * frame / 60);
+            assert_eq!(stop, StopReason::CyclesExhausted);
+            total += n
+        }
+        assert_eq!(total, 6000);
+        assert_eq!(limiter.cycles_due(Duration::from_secs(1)), 0); // No time has passed
+
+        // A ten second stall only earns one frame more than usual
+        assert_eq!(limiter.cycles_due(Duration::from_secs(11)), 200);
+        assert_eq!(limiter.cycles_due(Duration::from_secs(11) + Duration::from_millis(10)), 60);
+    }
+
+    #[test]
+    fn test_limiter_weighted() {
+        // Seven cycles a loop: push 0x400 takes three, loadw three, pop one
+        let mut cpu = CPU::boot(&crate::asm::assemble(&"push 0x400\nloadw\npop\n".repeat(1000)).unwrap()).unwrap();
+        let mut limiter = Limiter::new(ClockConfig { cycles_per_second: 6000, frames_per_second: 60 });
+        let mut total = 0;
+        for frame in 1..=60 {
+            let (n, _) = limiter.run(&mut cpu, Duration::from_secs(1) * frame / 60);
+            total += n;
+            assert!(cpu.cycle_count() <= 100 * frame as u64 + 2); // Never more than an instruction ahead
+        }
+        // 857 loops come to 5999 cycles, and one more push goes two over
+        assert_eq!(cpu.cycle_count(), 6002);
+        assert_eq!(total, 857 * 3 + 1);
+    }
+}
@@ -1,65 +1,439 @@
 use crate::opcodes::Opcode;
-use crate::opcodes::InvalidOpcode;
-use crate::memory::Memory;
+use crate::opcodes::{cycle_cost, InvalidOpcode, OpCategory};
+use crate::memory::{ImageTooLarge, Memory};
 use crate::address::Word;
 use crate::memory::PeekPoke;
+use crate::bus::MappedDevice;
+use crate::disassembler::disassemble_one;
+use crate::layout::{CALL_STACK_BASE, DATA_STACK_BASE, ENTRY_POINT, INTERRUPT_VECTOR};
+use std::collections::HashSet;
 use std::convert::TryFrom;
+use std::io::Write;
+use log::{debug, trace, warn};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
 
-struct CPU {
-    memory: Memory, // Main memory, all of it
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU<M = Memory> {
+    memory: M, // Everything the CPU can address: plain memory, or a bus of devices
+    config: Config, // Where the registers start, and so where the stacks' bottoms are
     pc: Word, // program counter, address of the low byte of the instruction
     dp: Word, // data pointer, address of the low byte of one cell above the data stack
     sp: Word, // stack pointer, address of the low byte of the return stack
     iv: Word, // interrupt vector
     int_enabled: bool, // interrupt enable bit
     halted: bool, // Whether the CPU is halted
+    waiting: bool, // Whether the CPU is paused by a waitv until the next vblank
+    rng: StdRng, // Source for the rand instruction
+    debug_out: Option<Box<dyn Write>>, // Where the debug instruction prints to, if anywhere
+    trace_out: Option<Box<dyn Write>>, // Where every instruction is traced to as it's stepped, if anywhere
+    stack_checks: bool, // Whether stack underflows fault, and debug builds assert the stack pointers stay in order
+    stacks_moved: bool, // Whether the program has put the stack pointers out of order itself, so they aren't asserted
+    max_call_depth: Option<usize>, // How deep the call stack may get before a push onto it faults, if there's a limit
+    fault: Option<ExecError>, // The first fault since the last reset, if any
+    step_fault: Option<ExecError>, // The fault the current step raised, if any
+    cycles: u64, // Cycles elapsed since the last reset
+    breakpoints: HashSet<Word>, // Addresses `run` stops at
+    watchpoints: HashSet<Word>, // Addresses `run` stops after a store to
+    watch_hit: Option<StopReason>, // The watchpoint the last step hit, if any
+    tick: Option<fn(&mut M) -> bool>, // Ticks the devices in memory, if they're ticked at all, and says whether they want an interrupt
 }
 
+/// Where a CPU's registers start out, when it's made and on every reset.
+/// The data stack grows up from `dp` and the call stack down from `sp`, so
+/// these are also the bottoms of the stacks. The default is the standard
+/// memory map in `layout`.
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
-struct Instruction {
-    opcode: Opcode,
-    arg: Option<u32>,
-    length: u8
+pub struct Config {
+    pub pc: Word,
+    pub dp: Word,
+    pub sp: Word,
+    pub iv: Word,
 }
 
-impl CPU {
-    fn new(memory: Memory) -> Self {
+impl Default for Config {
+    fn default() -> Self {
         Self {
-            memory,
-            pc: 1024.into(),
-            dp: 256.into(),
-            sp: 1024.into(),
-            iv: 1024.into(),
+            pc: ENTRY_POINT.into(),
+            dp: DATA_STACK_BASE.into(),
+            sp: CALL_STACK_BASE.into(),
+            iv: INTERRUPT_VECTOR.into(),
+        }
+    }
+}
+
+impl Config {
+    /// The registers a CPU starts with, and goes back to on reset: halted,
+    /// interrupts off, pointers from here
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            dp: self.dp,
+            sp: self.sp,
+            iv: self.iv,
             int_enabled: false,
             halted: true,
+            waiting: false,
+        }
+    }
+}
+
+/// A copy of the CPU's registers and flags, for looking at from outside
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Registers {
+    pub pc: Word,
+    pub dp: Word,
+    pub sp: Word,
+    pub iv: Word,
+    pub int_enabled: bool,
+    pub halted: bool,
+    pub waiting: bool,
+}
+
+/// Why `run` returned
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum StopReason {
+    Halted, // Ran a `hlt`, or was already halted
+    Fault(ExecError), // The last instruction raised this, and the CPU halted
+    WaitingForVblank,
+    CyclesExhausted,
+    Breakpoint(Word), // About to execute the instruction at this address
+    // Just executed a store touching a watched address. This is the address
+    // stored to, and what was there before and after: a byte for `store`, a
+    // word for `storew`.
+    Watchpoint { addr: Word, old: u32, new: u32 },
+}
+
+/// One instruction `trace_run` saw execute: where it was, and what it was
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct ExecutedInstruction {
+    pub pc: Word,
+    pub opcode: Opcode,
+    pub arg: Option<u32>,
+}
+
+/// Whether the CPU will keep executing if it's run
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum State {
+    Running,
+    Halted,
+    WaitingForVblank,
+}
+
+/// Something a program did that the CPU can't sensibly carry on from. Apart
+/// from an invalid opcode, which executes nothing, the instruction finishes
+/// with a defined result and then the CPU halts rather than carry on with
+/// garbage.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ExecError {
+    DivByZero, // A div or mod by zero
+    StackUnderflow, // Popped or peeked an empty data stack
+    StackOverflow, // Pushed onto either stack when the two had already met
+    PickOutOfRange, // A pick deeper than the data stack
+    CallDepthExceeded, // Pushed onto the call stack past the limit set with set_max_call_depth
+    InvalidOpcode(u8),
+}
+
+impl std::fmt::Display for ExecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExecError::DivByZero => write!(f, "Division by zero"),
+            ExecError::StackUnderflow => write!(f, "Data stack underflow"),
+            ExecError::StackOverflow => write!(f, "Stack overflow"),
+            ExecError::PickOutOfRange => write!(f, "Pick past the bottom of the data stack"),
+            ExecError::CallDepthExceeded => write!(f, "Call stack too deep"),
+            ExecError::InvalidOpcode(op) => InvalidOpcode(*op).fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
+impl From<InvalidOpcode> for ExecError {
+    fn from(e: InvalidOpcode) -> Self { ExecError::InvalidOpcode(e.0) }
+}
+
+/// Everything about a CPU and its memory that a program can see, for save
+/// states: restoring one puts the machine back exactly as it was.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub pc: u32,
+    pub dp: u32,
+    pub sp: u32,
+    pub iv: u32,
+    pub int_enabled: bool,
+    pub halted: bool,
+    pub waiting: bool,
+    pub memory: Box<Memory>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) struct Instruction {
+    pub(crate) opcode: Opcode,
+    pub(crate) arg: Option<u32>,
+    pub(crate) length: u8
+}
+
+impl<M: PeekPoke> CPU<M> {
+    pub fn new(memory: M) -> Self {
+        Self::with_config(memory, Config::default())
+    }
+
+    /// A CPU whose registers start from `config` rather than the standard
+    /// memory map, for programs linked somewhere else or wanting bigger stacks
+    pub fn with_config(memory: M, config: Config) -> Self {
+        Self::with_rng(memory, config, StdRng::from_entropy())
+    }
+
+    /// A CPU whose `rand` instruction produces the same sequence every time
+    /// for a given seed
+    pub fn new_seeded(memory: M, seed: u64) -> Self {
+        Self::with_rng(memory, Config::default(), StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(memory: M, config: Config, rng: StdRng) -> Self {
+        let Registers { pc, dp, sp, iv, int_enabled, halted, waiting } = config.registers();
+        Self {
+            memory,
+            config,
+            pc,
+            dp,
+            sp,
+            iv,
+            int_enabled,
+            halted,
+            waiting,
+            rng,
+            debug_out: None,
+            trace_out: None,
+            stack_checks: true,
+            stacks_moved: false,
+            max_call_depth: None,
+            fault: None,
+            step_fault: None,
+            cycles: 0,
+            breakpoints: HashSet::new(),
+            watchpoints: HashSet::new(),
+            watch_hit: None,
+            tick: None,
+        }
+    }
+
+    /// Puts the registers back as `config` says, and halts
+    pub fn reset(&mut self) {
+        let Registers { pc, dp, sp, iv, int_enabled, halted, waiting } = self.config.registers();
+        (self.pc, self.dp, self.sp, self.iv) = (pc, dp, sp, iv);
+        (self.int_enabled, self.halted, self.waiting) = (int_enabled, halted, waiting);
+        self.fault = None;
+        self.stacks_moved = false;
+        self.cycles = 0;
+    }
+
+    pub fn config(&self) -> Config { self.config }
+
+    pub fn memory(&self) -> &M { &self.memory }
+
+    pub fn memory_mut(&mut self) -> &mut M { &mut self.memory }
+
+    /// How many cycles have gone by since the last reset: each instruction
+    /// stepped adds its `cycle_cost`, and `advance_cycles` adds however
+    /// many the host lets pass
+    pub fn cycle_count(&self) -> u64 { self.cycles }
+
+    /// The words on the data stack, bottom first, so the top is last
+    pub fn data_stack(&self) -> Vec<Word> {
+        let mut stack = Vec::new();
+        let mut curr = self.config.dp;
+        while curr < self.dp {
+            stack.push(self.memory.peek24(curr).into());
+            curr += 3
+        }
+        stack
+    }
+
+    /// The word `n` down from the top of the data stack, 0 being the top, or
+    /// `None` if the stack isn't that deep
+    pub fn peek_data_stack(&self, n: usize) -> Option<Word> {
+        if n < self.data_depth() as usize { Some(self.nth_from_top(n)) } else { None }
+    }
+
+    /// The return addresses on the call stack, bottom first like
+    /// `data_stack`, so the innermost call's is last
+    pub fn call_stack(&self) -> Vec<Word> {
+        let mut stack = Vec::new();
+        let mut curr = self.config.sp;
+        while curr > self.sp {
+            curr -= 3;
+            stack.push(self.memory.peek24(curr).into());
+        }
+        stack
+    }
+
+    pub fn pc(&self) -> Word { self.pc }
+
+    /// Moves execution to `addr`; the next `step` fetches from there
+    pub fn set_pc(&mut self, addr: Word) { self.pc = addr }
+
+    pub fn dp(&self) -> Word { self.dp }
+
+    pub fn sp(&self) -> Word { self.sp }
+
+    pub fn is_halted(&self) -> bool { self.halted }
+
+    /// Halts the CPU as though it had executed a `hlt`
+    pub fn halt(&mut self) { self.halted = true }
+
+    /// Un-halts the CPU, so `run` carries on from `pc`, and forgets any fault
+    pub fn resume(&mut self) {
+        self.halted = false;
+        self.fault = None
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            dp: self.dp,
+            sp: self.sp,
+            iv: self.iv,
+            int_enabled: self.int_enabled,
+            halted: self.halted,
+            waiting: self.waiting,
+        }
+    }
+
+    /// Why the CPU halted, if it was a fault rather than a `hlt`: the first
+    /// one since the last reset or resume
+    pub fn fault(&self) -> Option<ExecError> { self.fault }
+
+    pub fn state(&self) -> State {
+        if self.halted {
+            State::Halted
+        } else if self.waiting {
+            State::WaitingForVblank
+        } else {
+            State::Running
+        }
+    }
+
+    /// Vectors to the interrupt handler at `iv`, pushing the current `pc` so
+    /// the handler can `ret` to it. Interrupts are disabled on entry, and the
+    /// handler is expected to `inton` again when it's ready for another one.
+    /// An interrupt also wakes a halted CPU, so a program can `hlt` to idle
+    /// until something happens, but not one halted by a fault: that stays
+    /// halted, dropping interrupts, until it's reset or resumed. With
+    /// interrupts disabled this does nothing; the interrupt is dropped, not
+    /// queued. If there's no room on the call stack for the return address,
+    /// the push faults and the CPU stays halted where it was rather than
+    /// enter a handler it couldn't return from. Returns whether the
+    /// interrupt was taken.
+    pub fn interrupt(&mut self) -> bool {
+        if self.fault.is_some() {
+            debug!("Interrupt at {:06x} dropped, the CPU has faulted", self.pc);
+            false
+        } else if self.int_enabled {
+            debug!("Interrupt at {:06x}, vectoring to {:06x}", self.pc, self.iv);
+            if !self.push_call(self.pc) {
+                return false
+            }
+            self.int_enabled = false;
+            self.halted = false;
+            self.pc = self.iv;
+            true
+        } else {
+            debug!("Interrupt at {:06x} dropped, interrupts are disabled", self.pc);
+            false
         }
     }
 
-    fn reset(&mut self) {
-        self.pc = 1024.into();
-        self.dp = 256.into();
-        self.sp = 1024.into();
-        self.iv = 1024.into();
-        self.int_enabled = false;
-        self.halted = true;
+    /// Sends the output of the `debug` instruction to `w`. Without this,
+    /// `debug` does nothing.
+    pub fn set_debug_output(&mut self, w: Box<dyn Write>) {
+        self.debug_out = Some(w)
+    }
+
+    /// Writes a line to `w` for every instruction `step` executes, before
+    /// executing it: its address, its disassembly, and the top of the data
+    /// stack, top first, like "000402: add 0x3 [000002]".
+    pub fn set_trace(&mut self, w: Box<dyn Write>) {
+        self.trace_out = Some(w)
+    }
+
+    /// With stack checks on, popping or peeking an empty data stack (one
+    /// whose `dp` is at 256) faults with `StackUnderflow` instead of
+    /// reading whatever is below it; the pop reads as 0 and `dp` stays put.
+    /// Likewise pushing onto either stack once `dp` has met `sp` halts it,
+    /// and the push is dropped rather than overwrite the other stack, and a
+    /// `pick` deeper than the data stack faults with `PickOutOfRange` and
+    /// pushes 0. Without checks, `pick` reads below the stack like any load.
+    /// Debug builds also assert after every instruction that the data stack
+    /// (growing up from 256) hasn't crossed the call stack (growing down
+    /// from 1024), to catch emulator bugs. A program can still move the
+    /// stacks anywhere with `setsdp`, or return past the bottom of the call
+    /// stack; that's legal, and the assertion stands down until a `setsdp`
+    /// or reset puts them back in order. That's on by default; turn it off
+    /// to run programs that deliberately move the stacks.
+    pub fn set_stack_checks(&mut self, enabled: bool) {
+        self.stack_checks = enabled
+    }
+
+    /// Caps the call stack at `max` words, so runaway recursion faults with
+    /// `CallDepthExceeded` long before it reaches the data stack. Like an
+    /// overflow, the push that would go past the limit is dropped. Return
+    /// addresses, interrupts and `pushr` all count. `None`, the default,
+    /// leaves the stacks meeting as the only limit.
+    pub fn set_max_call_depth(&mut self, max: Option<usize>) {
+        self.max_call_depth = max
+    }
+
+    /// How many words are on the call stack: one per call that hasn't
+    /// returned yet, plus anything put there with `pushr`
+    pub fn call_depth(&self) -> usize {
+        (u32::from(self.config.sp).saturating_sub(self.sp.into()) / 3) as usize
+    }
+
+    /// Signals that a frame has been drawn, releasing a CPU that's paused in
+    /// a `waitv` and, if interrupts are enabled, interrupting it, so a
+    /// program can run its game loop from the interrupt handler.
+    pub fn vblank(&mut self) {
+        self.waiting = false;
+        self.interrupt();
     }
 
     fn push_data<A: Into<u32>>(&mut self, word: A) {
+        if self.stacks_collide() {
+            return
+        }
         self.memory.poke24(self.dp, word.into());
         self.dp += 3;
     }
 
-    fn push_call<A: Into<u32>>(&mut self, word: A) {
+    // Whether the word was pushed, rather than dropped with a fault
+    fn push_call<A: Into<u32>>(&mut self, word: A) -> bool {
+        if self.stacks_collide() {
+            return false
+        }
+        if self.max_call_depth.is_some_and(|max| self.call_depth() >= max) {
+            self.raise(ExecError::CallDepthExceeded);
+            return false
+        }
         self.sp -= 3;
         self.memory.poke24(self.sp, word.into());
+        true
     }
 
     fn pop_data(&mut self) -> u32 {
+        if self.data_underflows() {
+            return 0
+        }
         self.dp -= 3;
         self.memory.peek24(self.dp)
     }
 
     fn pop_call(&mut self) -> u32 {
+        if self.sp >= self.config.sp {
+            self.stacks_moved = true
+        }
         let val = self.memory.peek24(self.sp);
         self.sp += 3;
         val
@@ -69,39 +443,234 @@ impl CPU {
         self.memory.peek24(self.sp)
     }
 
-    fn peek_data(&self) -> u32 {
-        self.memory.peek24(self.dp - 3)
+    fn peek_data(&mut self) -> u32 {
+        if self.data_underflows() {
+            return 0
+        }
+        self.nth_from_top(0).into()
+    }
+
+    // The word `n` down from the top of the data stack, 0 being the top,
+    // without checking that there are that many
+    fn nth_from_top(&self, n: usize) -> Word {
+        self.memory.peek24(self.dp - (n as i32 + 1) * 3).into()
+    }
+
+    // How many words are on the data stack
+    fn data_depth(&self) -> u32 {
+        u32::from(self.dp).saturating_sub(self.config.dp.into()) / 3
+    }
+
+    fn data_underflows(&mut self) -> bool {
+        let underflows = self.stack_checks && self.dp < self.config.dp + 3;
+        if underflows {
+            self.raise(ExecError::StackUnderflow)
+        }
+        underflows
+    }
+
+    // Whether there's no room left between the stacks for another word
+    fn stacks_collide(&mut self) -> bool {
+        let collides = self.stack_checks && self.dp + 3 > self.sp;
+        if collides {
+            self.raise(ExecError::StackOverflow)
+        }
+        collides
+    }
+
+    // Only the first fault of an instruction is reported; the rest of a
+    // faulting instruction usually faults again and isn't interesting
+    fn raise(&mut self, fault: ExecError) {
+        if self.step_fault.is_none() {
+            warn!("{} at {:06x}", fault, self.pc);
+            self.step_fault = Some(fault);
+            self.fault.get_or_insert(fault);
+        }
+        self.halted = true
+    }
+
+    fn stacks_in_order(&self) -> bool {
+        self.config.dp <= self.dp && self.dp <= self.sp && self.sp <= self.config.sp
+    }
+
+    // One line, like "pc 000400 data [000005 000003] call [000401]", with
+    // the data stack bottom-first and the call stack top-first
+    fn debug_dump(&mut self) {
+        if let Some(out) = &mut self.debug_out {
+            let mut data = Vec::new();
+            let mut curr = self.config.dp;
+            while curr < self.dp {
+                data.push(format!("{:06x}", self.memory.peek24(curr)));
+                curr += 3
+            }
+
+            let mut call = Vec::new();
+            let mut curr = self.sp;
+            while curr < self.config.sp {
+                call.push(format!("{:06x}", self.memory.peek24(curr)));
+                curr += 3
+            }
+
+            // Nothing useful to do if the host's writer fails
+            let _ = writeln!(out, "pc {:06x} data [{}] call [{}]", self.pc, data.join(" "), call.join(" "));
+        }
+    }
+
+    // How many data stack entries a trace line shows
+    const TRACE_DEPTH: usize = 4;
+
+    fn trace_instruction(&mut self) {
+        if let Some(out) = &mut self.trace_out {
+            let (text, _, _) = disassemble_one(&self.memory, self.pc);
+            let mut top = Vec::new();
+            let mut curr = self.dp;
+            while curr > self.config.dp && top.len() < Self::TRACE_DEPTH {
+                curr -= 3;
+                top.push(format!("{:06x}", self.memory.peek24(curr)))
+            }
+            let _ = writeln!(out, "{:06x}: {} [{}]", self.pc, text, top.join(" "));
+        }
     }
 
     fn fetch(&self) -> Result<Instruction, InvalidOpcode> {
-        let instruction = self.memory.peek(self.pc);
-        match Opcode::try_from(instruction >> 2) {
-            Ok(opcode) => {
-                let arg_length = instruction & 3;
-                if arg_length == 0 {
-                    Ok(Instruction {
-                        opcode: opcode,
-                        arg: None,
-                        length: 1
-                    })
-                } else {
-                    let mut arg = 0u32;
-                    for n in 0..arg_length {
-                        let mut b: u32 = self.memory.peek(self.pc + (n + 1) as i32) as u32;
-                        b = b << (8 * n);
-                        arg += b;
-                    }
-                    Ok(Instruction {
-                        opcode: opcode,
-                        arg: Some(arg),
-                        length: arg_length + 1
-                    })
-                }
-            },
-            Err(e) => Err(e)
+        decode(&self.memory, self.pc)
+    }
+
+    /// Fetches and executes the instruction at `pc`, then advances `pc` to
+    /// wherever that instruction left it. This runs even if the CPU is
+    /// halted, so a debugger can single-step past a `hlt`. An instruction
+    /// that faults still finishes, then halts the CPU and returns the fault.
+    pub fn step(&mut self) -> Result<State, ExecError> {
+        self.watch_hit = None;
+        self.step_fault = None;
+        let instruction = match self.fetch() {
+            Ok(instruction) => instruction,
+            Err(e) => {
+                self.raise(e.into());
+                return Err(e.into())
+            }
+        };
+        trace!("{:06x}: {:?} {:?}", self.pc, instruction.opcode, instruction.arg);
+        self.trace_instruction();
+        self.pc = self.execute(instruction);
+        self.cycles += cycle_cost(instruction.opcode, instruction.length - 1) as u64;
+        if let Some(tick) = self.tick {
+            if tick(&mut self.memory) {
+                self.interrupt();
+            }
+        }
+        debug_assert!(!self.stack_checks || self.stacks_moved || self.stacks_in_order(),
+                      "stack pointers out of order: dp {:#x} sp {:#x}", self.dp, self.sp);
+        match self.step_fault {
+            Some(fault) => Err(fault),
+            None => Ok(self.state())
+        }
+    }
+
+    /// Steps until the CPU halts, waits for vblank, reaches a breakpoint,
+    /// faults, or `max_instructions` instructions have run, returning the
+    /// number of instructions actually executed and why it stopped. A CPU
+    /// that isn't `Running` runs nothing. A breakpoint where the run starts
+    /// doesn't stop it, so after stopping at one, running again carries on
+    /// past it.
+    pub fn run(&mut self, max_instructions: usize) -> (usize, StopReason) {
+        self.run_until(max_instructions, u64::MAX)
+    }
+
+    /// Like `run`, but stops once `budget` cycles have gone by, counted the
+    /// way `cycle_count` counts them, rather than after some number of
+    /// instructions. Still returns how many instructions ran. Instructions
+    /// aren't split, so the last one can take the run a few cycles over.
+    pub fn run_cycles(&mut self, budget: u64) -> (usize, StopReason) {
+        self.run_until(usize::MAX, budget)
+    }
+
+    fn run_until(&mut self, max_instructions: usize, budget: u64) -> (usize, StopReason) {
+        let start = self.cycles;
+        let mut ran = 0;
+        loop {
+            match self.state() {
+                State::Halted => return (ran, StopReason::Halted),
+                State::WaitingForVblank => return (ran, StopReason::WaitingForVblank),
+                State::Running => {}
+            }
+            if ran == max_instructions || self.cycles.saturating_sub(start) >= budget {
+                return (ran, StopReason::CyclesExhausted)
+            }
+            if ran > 0 && self.breakpoints.contains(&self.pc) {
+                debug!("Breakpoint at {:06x}", self.pc);
+                return (ran, StopReason::Breakpoint(self.pc))
+            }
+            match self.step() {
+                // An opcode that doesn't decode never executed at all
+                Err(fault @ ExecError::InvalidOpcode(_)) => return (ran, StopReason::Fault(fault)),
+                Err(fault) => return (ran + 1, StopReason::Fault(fault)),
+                Ok(_) => ran += 1
+            }
+            if let Some(stop) = self.watch_hit.take() {
+                return (ran, stop)
+            }
+        }
+    }
+
+    /// Steps like `run`, recording each instruction as it goes, until the
+    /// CPU stops running, a fault, or `max` instructions. For profilers and
+    /// tracers that want the instructions as data rather than text. Unlike
+    /// `run` it doesn't stop at breakpoints or watchpoints. An instruction
+    /// that faults is recorded, unless it couldn't even be decoded.
+    pub fn trace_run(&mut self, max: usize) -> Vec<ExecutedInstruction> {
+        let mut executed = Vec::new();
+        while executed.len() < max && self.state() == State::Running {
+            if let Ok(instruction) = self.fetch() {
+                executed.push(ExecutedInstruction { pc: self.pc, opcode: instruction.opcode, arg: instruction.arg })
+            }
+            if self.step().is_err() {
+                break
+            }
+        }
+        executed
+    }
+
+    /// Makes `run` stop before executing the instruction at `addr`
+    pub fn add_breakpoint(&mut self, addr: Word) {
+        self.breakpoints.insert(addr);
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: Word) {
+        self.breakpoints.remove(&addr);
+    }
+
+    /// Makes `run` stop after any `store` or `storew` that writes the byte
+    /// at `addr`
+    pub fn add_watchpoint(&mut self, addr: Word) {
+        self.watchpoints.insert(addr);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: Word) {
+        self.watchpoints.remove(&addr);
+    }
+
+    // Stores the low `len` bytes of `val` at `addr`, for the store
+    // instructions, noting it if that hits a watchpoint. A word goes out as
+    // one `poke24`, so a device sees the whole word written at once.
+    fn store(&mut self, addr: Word, val: u32, len: i32) {
+        let watched = (0..len).any(|i| self.watchpoints.contains(&(addr + i)));
+        let old = if watched { self.peek_n(addr, len) } else { 0 };
+        match len {
+            3 => self.memory.poke24(addr, val),
+            _ => self.memory.poke(addr, val as u8)
+        }
+        if watched {
+            let new = self.peek_n(addr, len);
+            debug!("Watchpoint at {:06x}: {:x} -> {:x}", addr, old, new);
+            self.watch_hit = Some(StopReason::Watchpoint { addr, old, new })
         }
     }
 
+    fn peek_n(&self, addr: Word, len: i32) -> u32 {
+        (0..len).map(|i| (self.memory.peek(addr + i) as u32) << (8 * i)).sum()
+    }
+
     fn execute(&mut self, instruction: Instruction) -> Word {
         if let Some(arg) = instruction.arg {
             self.push_data(arg)
@@ -113,59 +682,122 @@ impl CPU {
 
             match instruction.opcode {
                 Opcode::Add => { self.push_data(x + y) }
-                Opcode::Sub => { self.push_data(y - x) }
-                Opcode::Mul => { self.push_data(y * x) }
-                Opcode::Div => { self.push_data(y / x) }
-                Opcode::Mod => { self.push_data(y % x) }
+                Opcode::Sub => { self.push_data(y.wrapping_sub(x)) }
+                Opcode::Mul => { self.push_data(y.wrapping_mul(x)) }
+                // The high half of the 48-bit product, the half `mul` drops
+                Opcode::Mulhi => { self.push_data((((y as u64) * (x as u64)) >> 24) as u32) }
+                // For multi-word math: the 24-bit result, then the carry (or
+                // borrow) out of it, 0 or 1, on top where the next word's
+                // add can pick it up
+                Opcode::Addc => {
+                    let sum = y + x;
+                    self.push_data(sum & 0xffffff);
+                    self.push_data(sum >> 24)
+                }
+                Opcode::Subc => {
+                    self.push_data(y.wrapping_sub(x) & 0xffffff);
+                    self.push_data(bool_as_word(x > y))
+                }
+                // Dividing by zero faults, but still leaves a result: the
+                // quotient is all ones and the remainder is the dividend, so
+                // `y == x * (y / x) + y % x` still holds.
+                Opcode::Div => {
+                    if x == 0 { self.raise(ExecError::DivByZero) }
+                    self.push_data(y.checked_div(x).unwrap_or(0xffffff))
+                }
+                Opcode::Mod => {
+                    if x == 0 { self.raise(ExecError::DivByZero) }
+                    self.push_data(y.checked_rem(x).unwrap_or(y))
+                }
                 Opcode::And => { self.push_data(y & x) }
                 Opcode::Or => { self.push_data(y | x) }
                 Opcode::Xor => { self.push_data(y ^ x) }
                 Opcode::Gt => { self.push_data(bool_as_word(y > x)) }
                 Opcode::Lt => { self.push_data(bool_as_word(y < x)) }
-                Opcode::Agt => { self.push_data(bool_as_word(word_as_signed(y) > word_as_signed(x))) }
-                Opcode::Alt => { self.push_data(bool_as_word(word_as_signed(y) < word_as_signed(x))) }
-                Opcode::Lshift => { self.push_data(y << x) }
-                Opcode::Rshift => { self.push_data(y >> x) }
+                Opcode::Agt => { self.push_data(bool_as_word(Word::from(y).as_signed() > Word::from(x).as_signed())) }
+                Opcode::Alt => { self.push_data(bool_as_word(Word::from(y).as_signed() < Word::from(x).as_signed())) }
+                // Shifting by the word width or more shifts everything out
+                Opcode::Lshift => { self.push_data(y.checked_shl(x).unwrap_or(0)) }
+                Opcode::Rshift => { self.push_data(y.checked_shr(x).unwrap_or(0)) }
                 Opcode::Arshift => {
-                    if y & 0x800000 != 0 {
-                        let mut shifted = y;
-                        for _ in 0..x {
-                            shifted = shifted >> 1 | 0x800000;
-                        }
-                        self.push_data(shifted)
-                    } else {
-                        self.push_data(y >> x)
-                    }
+                    // By 23 every bit is a copy of the sign, so shifting further changes nothing
+                    let shifted = Word::from(y).as_signed() >> x.min(23);
+                    self.push_data(shifted as u32 & 0xffffff)
                 }
                 Opcode::Swap => {
                     self.push_data(x);
                     self.push_data(y)
                 }
-                Opcode::Store => { self.memory.poke(x.into(), y as u8) }
-                Opcode::Storew => { self.memory.poke24(x.into(), y) }
+                Opcode::Nip => { self.push_data(x) }
+                Opcode::Tuck => {
+                    self.push_data(x);
+                    self.push_data(y);
+                    self.push_data(x)
+                }
+                Opcode::Store => { self.store(x.into(), y & 0xff, 1) }
+                Opcode::Storew => { self.store(x.into(), y, 3) }
                 Opcode::Setsdp => {
                     self.dp = x.into();
-                    self.sp = y.into()
+                    self.sp = y.into();
+                    self.stacks_moved = !self.stacks_in_order()
+                }
+                Opcode::Brz if y == 0 => { return self.pc.offset(Word::from(x).as_signed()) }
+                Opcode::Brnz if y != 0 => { return self.pc.offset(Word::from(x).as_signed()) }
+                Opcode::Brz | Opcode::Brnz => { /* Not taken */ }
+                Opcode::Not | Opcode::Pop | Opcode::Dup | Opcode::Pick | Opcode::Rot |
+                    Opcode::Load | Opcode::Loadw | Opcode::Pushr | Opcode::Neg | Opcode::Incr |
+                    Opcode::Decr | Opcode::Nop | Opcode::Rand | Opcode::Sdp | Opcode::Popr |
+                    Opcode::Peekr | Opcode::Depth | Opcode::Cycles | Opcode::Jmp | Opcode::Jmpr |
+                    Opcode::Call | Opcode::Ret | Opcode::Hlt | Opcode::Inton | Opcode::Intoff |
+                    Opcode::Setiv | Opcode::Debug | Opcode::Waitv | Opcode::Flags | Opcode::Setflags => {
+                    unreachable!("{} isn't binary", instruction.opcode)
                 }
-                Opcode::Brz => { if y == 0 { return self.pc + word_as_signed(x) } }
-                Opcode::Brnz => { if y != 0 { return self.pc + word_as_signed(x) } }
-                _ => {} // This can never happen
             }
             self.pc + instruction.length as i32
         } else {
             match instruction.opcode {
                 Opcode::Nop => { /* No action required */ }
-                Opcode::Rand => {} // TODO remove this whole instruction
+                Opcode::Rand => {
+                    let r = self.rng.gen::<u32>() & 0xffffff;
+                    self.push_data(r)
+                }
                 Opcode::Not => {
                     let x = self.pop_data();
                     self.push_data(bool_as_word(x == 0))
                 }
+                Opcode::Incr => {
+                    let x = self.pop_data();
+                    self.push_data(x.wrapping_add(1))
+                }
+                Opcode::Decr => {
+                    let x = self.pop_data();
+                    self.push_data(x.wrapping_sub(1))
+                }
+                // The same count as cycle_count, weighted by cycle_cost rather
+                // than one per instruction, up to but not including this one
+                Opcode::Cycles => { self.push_data(self.cycles as u32 & 0xffffff) }
+                Opcode::Depth => {
+                    let depth = self.data_depth();
+                    self.push_data(depth)
+                }
+                Opcode::Neg => {
+                    let x = Word::from(self.pop_data());
+                    self.push_data(-x)
+                }
                 Opcode::Pop => { self.pop_data(); }
-                Opcode::Dup => { self.push_data(self.peek_data()) }
+                Opcode::Dup => {
+                    let x = self.peek_data();
+                    self.push_data(x)
+                }
                 Opcode::Pick => {
                     let index = self.pop_data();
-                    let val = self.memory.peek24(self.dp - (index as i32 + 1) * 3);
-                    self.push_data(val)
+                    if self.stack_checks && index >= self.data_depth() {
+                        self.raise(ExecError::PickOutOfRange);
+                        self.push_data(0u32)
+                    } else {
+                        let val = self.nth_from_top(index as usize);
+                        self.push_data(val)
+                    }
                 }
                 Opcode::Rot => {
                     let x = self.pop_data();
@@ -177,8 +809,8 @@ impl CPU {
                 }
                 Opcode::Jmp => { return self.pop_data().into() }
                 Opcode::Jmpr => {
-                    let x = word_as_signed(self.pop_data());
-                    return self.pc + x
+                    let x = Word::from(self.pop_data()).as_signed();
+                    return self.pc.offset(x)
                 }
                 Opcode::Call => {
                     let x = self.pop_data();
@@ -186,7 +818,10 @@ impl CPU {
                     return x.into()
                 }
                 Opcode::Ret => { return self.pop_call().into() }
-                Opcode::Hlt => { self.halted = true }
+                Opcode::Hlt => {
+                    debug!("Halted at {:06x}", self.pc);
+                    self.halted = true
+                }
                 Opcode::Load => {
                     let x = self.pop_data();
                     self.push_data(self.memory.peek(x.into()) as u32)
@@ -195,16 +830,35 @@ impl CPU {
                     let x = self.pop_data();
                     self.push_data(self.memory.peek24(x.into()))
                 }
-                Opcode::Inton => { self.int_enabled = true }
-                Opcode::Intoff => { self.int_enabled = false }
+                Opcode::Inton => {
+                    debug!("Interrupts enabled at {:06x}", self.pc);
+                    self.int_enabled = true
+                }
+                Opcode::Intoff => {
+                    debug!("Interrupts disabled at {:06x}", self.pc);
+                    self.int_enabled = false
+                }
                 Opcode::Setiv => { self.iv = self.pop_data().into() }
+                // Bit 0 is int_enabled and bit 1 halted, which reads as 0
+                // since the CPU is running, so a handler can save the state
+                // it interrupted and put it back with setflags
+                Opcode::Flags => {
+                    let flags = self.int_enabled as u32 | (self.halted as u32) << 1;
+                    self.push_data(flags)
+                }
+                // Setting halted halts, like a hlt
+                Opcode::Setflags => {
+                    let flags = self.pop_data();
+                    self.int_enabled = flags & 1 != 0;
+                    self.halted |= flags & 2 != 0
+                }
                 Opcode::Sdp => {
                     self.push_data(self.sp);
                     self.push_data(self.dp + 3) // The +3 accounts for the word we're about to push
                 }
                 Opcode::Pushr => {
                     let x = self.pop_data();
-                    self.push_call(x)
+                    self.push_call(x);
                 }
                 Opcode::Popr => {
                     let r = self.pop_call();
@@ -214,30 +868,160 @@ impl CPU {
                     let r = self.peek_call();
                     self.push_data(r)
                 }
-                Opcode::Debug => { /* TODO This should print the stack or something */ }
-                _ => {} // This can never happen
+                Opcode::Debug => { self.debug_dump() }
+                Opcode::Waitv => {
+                    debug!("Waiting for vblank at {:06x}", self.pc);
+                    self.waiting = true
+                }
+                Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod | Opcode::And |
+                    Opcode::Or | Opcode::Xor | Opcode::Gt | Opcode::Lt | Opcode::Agt | Opcode::Alt |
+                    Opcode::Lshift | Opcode::Rshift | Opcode::Arshift | Opcode::Swap | Opcode::Brz |
+                    Opcode::Brnz | Opcode::Store | Opcode::Storew | Opcode::Setsdp | Opcode::Addc |
+                    Opcode::Subc | Opcode::Nip | Opcode::Tuck | Opcode::Mulhi => {
+                    unreachable!("{} is binary", instruction.opcode)
+                }
             }
             self.pc + instruction.length as i32
         }
     }
 }
 
-impl Opcode {
-    fn is_binary(self) -> bool {
-        use Opcode::*;
-        self != Nop && self != Not && self != Rand && self != Pop && self != Dup && self != Pick &&
-            self != Rot && self != Jmp && self != Jmpr && self != Call && self != Ret &&
-            self != Hlt && self != Load && self != Loadw && self != Inton && self != Intoff &&
-            self != Setiv && self != Sdp && self != Pushr && self != Popr && self != Peekr &&
-            self != Debug
+// One line for a quick look while debugging: the registers, the flags as 0 or
+// 1, and the top of the data stack, top first, as deep as a trace line shows
+impl<M: PeekPoke> std::fmt::Display for CPU<M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let top: Vec<String> = (0..Self::TRACE_DEPTH)
+            .map_while(|n| self.peek_data_stack(n))
+            .map(|w| format!("{:06x}", w))
+            .collect();
+        write!(f, "pc {:06x} dp {:06x} sp {:06x} iv {:06x} int {} halted {} waiting {} top [{}]",
+               self.pc, self.dp, self.sp, self.iv,
+               self.int_enabled as u8, self.halted as u8, self.waiting as u8, top.join(" "))
+    }
+}
+
+impl CPU<Memory> {
+    /// A CPU with `image` loaded at the entry point of otherwise zeroed
+    /// memory, already running it, or an error if the image doesn't fit
+    pub fn boot(image: &[u8]) -> Result<Self, ImageTooLarge> {
+        let mut cpu = CPU::new(Memory::with_image(ENTRY_POINT.into(), image)?);
+        cpu.resume();
+        Ok(cpu)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc.into(),
+            dp: self.dp.into(),
+            sp: self.sp.into(),
+            iv: self.iv.into(),
+            int_enabled: self.int_enabled,
+            halted: self.halted,
+            waiting: self.waiting,
+            memory: Box::new(self.memory.clone()),
+        }
+    }
+
+    /// Puts the registers and memory back how they were in `snapshot`. The
+    /// host's settings, like the debug output and stack checks, are kept.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.pc = snapshot.pc.into();
+        self.dp = snapshot.dp.into();
+        self.sp = snapshot.sp.into();
+        self.iv = snapshot.iv.into();
+        self.int_enabled = snapshot.int_enabled;
+        self.halted = snapshot.halted;
+        self.waiting = snapshot.waiting;
+        self.memory = *snapshot.memory;
+        self.fault = None;
+        self.stacks_moved = !self.stacks_in_order();
+    }
+
+    /// Copies a program image into memory at `addr` and starts the CPU
+    /// running there. Returns how many bytes fit, like `Memory::load`.
+    pub fn load_program(&mut self, addr: Word, image: &[u8]) -> usize {
+        let written = self.memory.load(addr, image);
+        self.pc = addr;
+        self.resume();
+        written
+    }
+}
+
+impl<M: MappedDevice> CPU<M> {
+    /// Resets everything, like the machine's reset line: first every device
+    /// on the bus, then the CPU itself as `reset` does. `reset` alone leaves
+    /// the devices as they were, since it can't know there are any.
+    pub fn reset_all(&mut self) {
+        self.memory.reset();
+        self.reset()
+    }
+
+    /// Ticks the devices on the bus once per `step`. The tick happens after
+    /// the instruction executes, so an instruction sees its devices in the
+    /// state the previous tick left them, and they react to whatever it
+    /// stored before the next instruction runs. An interrupt a device
+    /// requests during its tick is taken right away, before the next
+    /// instruction.
+    pub fn set_device_ticks(&mut self, enabled: bool) {
+        self.tick = if enabled { Some(tick_devices::<M>) } else { None }
     }
+
+    /// Lets `cycles` cycles go by without executing anything, as though the
+    /// CPU were halted or waiting, ticking the devices once per cycle and
+    /// delivering any interrupts they request. Returns how many interrupts
+    /// the CPU actually took; requests that arrive while interrupts are
+    /// disabled, or that there's no room on the call stack for, are
+    /// dropped, the same as during `step`.
+    pub fn advance_cycles(&mut self, cycles: usize) -> usize {
+        let mut taken = 0;
+        for _ in 0..cycles {
+            self.cycles += 1;
+            if tick_devices(&mut self.memory) && self.interrupt() {
+                taken += 1
+            }
+        }
+        taken
+    }
+}
+
+fn tick_devices<M: MappedDevice>(memory: &mut M) -> bool {
+    memory.tick();
+    memory.take_interrupt()
+}
+
+impl Opcode {
+    fn is_binary(self) -> bool { self.category() == OpCategory::Binary }
 }
 
-fn word_as_signed(word: u32) -> i32 {
-    if word & 0x800000 != 0 {
-        -(((word ^ 0xffffff) + 1) as i32)
-    } else {
-        word as i32
+/// Decodes the instruction at `addr`: the opcode in the high six bits of the
+/// first byte, the number of argument bytes in the low two, and then that
+/// many bytes of little-endian argument.
+pub(crate) fn decode<P: PeekPoke>(memory: &P, addr: Word) -> Result<Instruction, InvalidOpcode> {
+    let instruction = memory.peek(addr);
+    match Opcode::try_from(instruction >> 2) {
+        Ok(opcode) => {
+            let arg_length = instruction & 3;
+            if arg_length == 0 {
+                Ok(Instruction {
+                    opcode,
+                    arg: None,
+                    length: 1
+                })
+            } else {
+                let mut arg = 0u32;
+                for n in 0..arg_length {
+                    let mut b: u32 = memory.peek(addr + (n + 1) as i32) as u32;
+                    b <<= 8 * n;
+                    arg += b;
+                }
+                Ok(Instruction {
+                    opcode,
+                    arg: Some(arg),
+                    length: arg_length + 1
+                })
+            }
+        },
+        Err(e) => Err(e)
     }
 }
 
@@ -246,27 +1030,18 @@ fn bool_as_word(flag: bool) -> u32 { if flag { 1 } else { 0 } }
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bus::{Bus, Device};
     use Opcode::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
-    impl CPU {
+    impl<M: PeekPoke> CPU<M> {
         fn get_stack(&self) -> Vec<u32> {
-            let mut v = Vec::new();
-            let mut curr = Word::from(256);
-            while curr < self.dp {
-                v.push(self.memory.peek24(curr));
-                curr += 3
-            }
-            v
+            self.data_stack().into_iter().map(u32::from).collect()
         }
 
         fn get_call(&self) -> Vec<u32> {
-            let mut v = Vec::new();
-            let mut curr = Word::from(1024);
-            while curr > self.sp {
-                curr -= 3;
-                v.push(self.memory.peek24(curr));
-            }
-            v
+            self.call_stack().into_iter().map(u32::from).collect()
         }
     }
 
@@ -274,7 +1049,7 @@ mod tests {
     {
         let mut cpu = CPU::new(Memory::default());
         given(&mut cpu);
-        let new_pc = cpu.execute(Instruction{ opcode: opcode, arg: None, length: 1 });
+        let new_pc = cpu.execute(Instruction{ opcode, arg: None, length: 1 });
         cpu.pc = new_pc;
         pred(&mut cpu)
     }
@@ -290,7 +1065,7 @@ mod tests {
     fn call_stack_opcode_test(given: Vec<u32>, given_r: Vec<u32>, opcode: Opcode, expected: Vec<u32>, expected_r: Vec<u32>, pc: Word) {
         predicate_opcode_test(opcode, |cpu| {
             for i in given.into_iter() { cpu.push_data(i) }
-            for i in given_r.into_iter() { cpu.push_call(i) }
+            for i in given_r.into_iter() { cpu.push_call(i); }
         }, |cpu| {
             assert_eq!(cpu.get_stack(), expected);
             assert_eq!(cpu.get_call(), expected_r);
@@ -340,45 +1115,280 @@ mod tests {
         simple_opcode_test(vec![5, 3], Mul, vec![15]);
         simple_opcode_test(vec![8, 3], Div, vec![2]);
         simple_opcode_test(vec![10, 3], Mod, vec![1]);
+        simple_opcode_test(vec![3, 5], Sub, vec![to_word(-2)]);
+        simple_opcode_test(vec![0xffffff, 0xffffff], Mul, vec![1]);
+        simple_opcode_test(vec![5, 3], Mulhi, vec![0]);
+        simple_opcode_test(vec![0xffffff, 0xffffff], Mulhi, vec![0xfffffe]);
+        simple_opcode_test(vec![0x123456, 0x789abc], Mulhi, vec![0x089389]);
+        simple_opcode_test(vec![5], Neg, vec![to_word(-5)]);
+        simple_opcode_test(vec![to_word(-5)], Neg, vec![5]);
+        simple_opcode_test(vec![0], Neg, vec![0]);
+        simple_opcode_test(vec![5], Incr, vec![6]);
+        simple_opcode_test(vec![0xffffff], Incr, vec![0]);
+        simple_opcode_test(vec![5], Decr, vec![4]);
+        simple_opcode_test(vec![0], Decr, vec![0xffffff]);
+    }
+
+    #[test]
+    fn test_full_width_multiply() {
+        // mul and mulhi together give back the whole 48-bit product
+        for (x, y) in [(0x123456u32, 0x789abcu32), (0xffffff, 2), (0x800000, 0x800000), (7, 9)] {
+            let mut cpu = CPU::boot(&crate::asm::assemble("pick 1\npick 1\nmulhi\npick 2\npick 2\nmul\nhlt").unwrap()).unwrap();
+            cpu.push_data(x);
+            cpu.push_data(y);
+            cpu.run(100);
+            let stack = cpu.get_stack();
+            assert_eq!((stack[2] as u64) << 24 | stack[3] as u64, x as u64 * y as u64);
+        }
+    }
+
+    #[test]
+    fn test_carry() {
+        simple_opcode_test(vec![5, 3], Addc, vec![8, 0]);
+        simple_opcode_test(vec![0xffffff, 1], Addc, vec![0, 1]);
+        simple_opcode_test(vec![0xffffff, 0xffffff], Addc, vec![0xfffffe, 1]);
+        simple_opcode_test(vec![5, 3], Subc, vec![2, 0]);
+        simple_opcode_test(vec![3, 5], Subc, vec![to_word(-2), 1]);
+        simple_opcode_test(vec![0, 0xffffff], Subc, vec![1, 1]);
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        simple_opcode_test(vec![5, 0], Div, vec![0xffffff]);
+        simple_opcode_test(vec![5, 0], Mod, vec![5]);
+        simple_opcode_test(vec![0, 0], Div, vec![0xffffff]);
+        simple_opcode_test(vec![0, 0], Mod, vec![0]);
     }
 
     #[test]
     fn test_stack_manipulation() {
         simple_opcode_test(vec![5], Dup, vec![5, 5]);
         simple_opcode_test(vec![5, 3], Swap, vec![3, 5]);
+        simple_opcode_test(vec![5, 3], Nip, vec![3]);
+        simple_opcode_test(vec![5, 3], Tuck, vec![3, 5, 3]);
         simple_opcode_test(vec![10, 20, 30, 2], Pick, vec![10, 20, 30, 10]);
         simple_opcode_test(vec![1, 4, 9], Rot, vec![4, 9, 1]);
         simple_opcode_test(vec![1, 4, 9], Pop, vec![1, 4]);
+        simple_opcode_test(vec![1, 4, 9], Depth, vec![1, 4, 9, 3]);
+        simple_opcode_test(vec![], Depth, vec![0]);
     }
 
     #[test]
-    fn test_basic_ops() {
-        control_flow_opcode_test(vec![], Nop, 1025);
-        simple_opcode_test(vec![2], Nop, vec![2]);
-        predicate_opcode_test(Hlt, |_| { }, |cpu| { assert!(cpu.halted) })
-    }
+    fn test_rand() {
+        let rolls = |seed| {
+            let mut cpu = CPU::new_seeded(Memory::default(), seed);
+            for _ in 0..5 {
+                cpu.execute(Instruction { opcode: Rand, arg: None, length: 1 });
+            }
+            cpu.get_stack()
+        };
 
-    #[test]
-    fn test_branching_jumping() {
-        control_flow_opcode_test(vec![1234], Jmp, 1234);
-        control_flow_opcode_test(vec![35], Jmpr, 1024 + 35);
-        control_flow_opcode_test(vec![to_word(-3)], Jmpr, 1024 - 3);
-        control_flow_opcode_test(vec![0, 35], Brnz, 1024 + 1);
-        control_flow_opcode_test(vec![17, 35], Brnz, 1024 + 35);
-        control_flow_opcode_test(vec![5, 35], Brz, 1024 + 1);
-        control_flow_opcode_test(vec![0, 35], Brz, 1024 + 35);
+        let a = rolls(1234);
+        assert_eq!(a.len(), 5);
+        assert_eq!(a, rolls(1234));
+        assert_ne!(a, rolls(4321));
+        assert!(a.iter().all(|&r| r <= 0xffffff));
     }
 
-    #[test]
-    fn test_memory() {
-        memory_opcode_test(vec![2048], vec![123], Load, vec![123], None);
-        memory_opcode_test(vec![2048], vec![0x12, 0x34, 0x56], Loadw, vec![0x123456], None);
-        memory_opcode_test(vec![100, 2048], vec![0x12, 0x34, 0x56], Store, vec![], Some(vec![100, 0x34, 0x56]));
-        memory_opcode_test(vec![0x112233, 2048], vec![0x12, 0x34, 0x56], Storew, vec![], Some(vec![0x33, 0x22, 0x11]));
+    // A writer the test can still read after handing it to the CPU
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> { self.0.borrow_mut().write(buf) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
     }
 
     #[test]
-    fn test_logic() {
+    fn test_debug_output() {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = CPU::new(Memory::default());
+        cpu.push_data(5u32);
+        cpu.push_data(0x123456u32);
+        cpu.push_call(0x401u32);
+        cpu.push_call(0x502u32);
+
+        cpu.execute(Instruction { opcode: Debug, arg: None, length: 1 }); // Silent by default
+        cpu.set_debug_output(Box::new(SharedBuffer(buffer.clone())));
+        cpu.execute(Instruction { opcode: Debug, arg: None, length: 1 });
+
+        let output = String::from_utf8(buffer.borrow().clone()).unwrap();
+        assert_eq!(output, "pc 000400 data [000005 123456] call [000502 000401]\n");
+        assert_eq!(cpu.get_stack(), vec![5, 0x123456]);
+    }
+
+    #[test]
+    fn test_trace() {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = CPU::new(Memory::default());
+        cpu.load_program(1024.into(), &crate::asm::assemble("push 2\nadd 3\nhlt").unwrap());
+        cpu.step().unwrap(); // Untraced
+        cpu.set_pc(1024.into());
+        cpu.set_trace(Box::new(SharedBuffer(buffer.clone())));
+        assert_eq!(cpu.run(10), (3, StopReason::Halted));
+
+        let output = String::from_utf8(buffer.borrow().clone()).unwrap();
+        assert_eq!(output, "000400: nop 0x2 [000002]\n000402: add 0x3 [000002 000002]\n000404: hlt [000005 000002]\n");
+    }
+
+    #[test]
+    fn test_boot() {
+        let mut cpu = CPU::boot(&crate::asm::assemble("push 7\nhlt").unwrap()).unwrap();
+        assert_eq!(cpu.state(), State::Running);
+        assert_eq!(cpu.fetch(), Ok(Instruction { opcode: Nop, arg: Some(7), length: 2 }));
+        assert_eq!(cpu.step(), Ok(State::Running));
+        assert_eq!(cpu.get_stack(), vec![7]);
+        assert_eq!(cpu.step(), Ok(State::Halted));
+
+        // Memory ends 0x20000 - 0x400 bytes past the entry point
+        assert!(CPU::boot(&vec![0; 0x20000 - 0x400]).is_ok());
+        let too_big = vec![0; 0x20000 - 0x400 + 1];
+        assert_eq!(CPU::boot(&too_big).err(), Some(ImageTooLarge { addr: 0x400.into(), len: too_big.len() }));
+    }
+
+    #[test]
+    fn test_cycles_opcode() {
+        // Two pushes with a byte of argument each, then an add: three
+        // instructions, but five cycles
+        let mut cpu = CPU::boot(&crate::asm::assemble("push 1\npush 2\nadd\ncycles\nhlt").unwrap()).unwrap();
+        cpu.run(100);
+        assert_eq!(cpu.get_stack(), vec![3, 2 + 2 + 1]);
+        assert_eq!(cpu.cycle_count(), 5 + 1 + 1); // The cycles and the hlt too
+
+        cpu.cycles = 0x1000005;
+        cpu.resume();
+        cpu.set_pc(0x405.into());
+        cpu.run(1);
+        assert_eq!(cpu.get_stack(), vec![3, 5, 5]); // Only the low 24 bits
+
+        // A load costs three
+        let mut cpu = CPU::boot(&crate::asm::assemble("push 0x10\nload\npop\ncycles").unwrap()).unwrap();
+        cpu.run(4);
+        assert_eq!(cpu.get_stack(), vec![2 + 3 + 1]);
+    }
+
+    #[test]
+    fn test_flags_opcodes() {
+        // Save the flags, turn interrupts on and read them again, then put the saved ones back
+        let mut cpu = CPU::boot(&crate::asm::assemble("flags\ninton\nflags\npick 1\nsetflags\nflags\nhlt").unwrap()).unwrap();
+        cpu.run(100);
+        assert_eq!(cpu.get_stack(), vec![0, 1, 0]);
+        assert!(!cpu.int_enabled);
+
+        // Restoring with interrupts on
+        let mut cpu = CPU::boot(&crate::asm::assemble("push 1\nsetflags\nflags\nhlt").unwrap()).unwrap();
+        cpu.run(100);
+        assert_eq!(cpu.get_stack(), vec![1]);
+        assert!(cpu.int_enabled);
+
+        // And setting halted stops the CPU there
+        let mut cpu = CPU::boot(&crate::asm::assemble("push 3\nsetflags\npush 5").unwrap()).unwrap();
+        assert_eq!(cpu.run(100), (2, StopReason::Halted));
+        assert!(cpu.int_enabled);
+        assert!(cpu.get_stack().is_empty());
+    }
+
+    #[test]
+    fn test_peek_data_stack() {
+        let mut cpu = CPU::new(Memory::default());
+        assert_eq!(cpu.peek_data_stack(0), None);
+        cpu.push_data(10u32);
+        cpu.push_data(20u32);
+        cpu.push_data(30u32);
+        assert_eq!(cpu.peek_data_stack(0), Some(30.into()));
+        assert_eq!(cpu.peek_data_stack(2), Some(10.into()));
+        assert_eq!(cpu.peek_data_stack(3), None);
+        assert_eq!(cpu.peek_data_stack(usize::MAX), None);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = Config { pc: 0x2000.into(), dp: 0x800.into(), sp: 0x1000.into(), iv: 0x3000.into() };
+        let mut cpu = CPU::with_config(Memory::default(), config);
+        assert_eq!((cpu.pc, cpu.dp, cpu.sp, cpu.iv), (config.pc, config.dp, config.sp, config.iv));
+        assert_eq!(cpu.config(), config);
+
+        cpu.push_data(5u32);
+        cpu.push_call(6u32);
+        assert_eq!(cpu.data_stack(), vec![5.into()]);
+        assert_eq!(cpu.call_stack(), vec![6.into()]);
+        assert_eq!(cpu.peek_data_stack(1), None);
+
+        cpu.pc = 0x1234.into();
+        cpu.iv = 0.into();
+        cpu.reset();
+        assert_eq!((cpu.pc, cpu.dp, cpu.sp, cpu.iv), (config.pc, config.dp, config.sp, config.iv));
+        assert!(cpu.data_stack().is_empty());
+    }
+
+    #[test]
+    fn test_display() {
+        let mut cpu = CPU::boot(&crate::asm::assemble("push 1\npush 2\npush 3\npush 4\npush 5\ninton\nhlt").unwrap()).unwrap();
+        assert_eq!(cpu.to_string(),
+                   "pc 000400 dp 000100 sp 000400 iv 000400 int 0 halted 0 waiting 0 top []");
+        cpu.run(100);
+        assert_eq!(cpu.to_string(),
+                   "pc 00040c dp 00010f sp 000400 iv 000400 int 1 halted 1 waiting 0 top [000005 000004 000003 000002]");
+    }
+
+    #[test]
+    fn test_reset_matches_new() {
+        let config = Config { pc: 0x2000.into(), ..Config::default() };
+        let mut cpu = CPU::with_config(Memory::default(), config);
+        let initial = cpu.registers();
+        assert_eq!(initial, config.registers());
+
+        cpu.load_program(0x2000.into(), &crate::asm::assemble("push 3\ninton\npush 4\nhlt").unwrap());
+        cpu.run(4);
+        assert_ne!(cpu.registers(), initial);
+        cpu.reset();
+        assert_eq!(cpu.registers(), initial);
+        assert_eq!(cpu.cycles, 0);
+    }
+
+    #[test]
+    fn test_trace_run() {
+        let program = crate::asm::assemble("push 2\nsub 1\ndup\nbrnz -3\nhlt").unwrap();
+        let mut cpu = CPU::new(Memory::default());
+        cpu.load_program(1024.into(), &program);
+        assert_eq!(cpu.trace_run(3).len(), 3);
+
+        cpu.load_program(1024.into(), &program);
+        let executed: Vec<(u32, Opcode)> = cpu.trace_run(100).iter().map(|e| (e.pc.into(), e.opcode)).collect();
+        assert_eq!(executed, vec![(0x400, Nop), (0x402, Sub), (0x404, Dup), (0x405, Brnz),
+                                  (0x402, Sub), (0x404, Dup), (0x405, Brnz), (0x409, Hlt)]);
+        assert!(cpu.trace_run(100).is_empty()); // Halted
+
+        cpu.load_program(1024.into(), &program);
+        assert_eq!(cpu.trace_run(2)[1], ExecutedInstruction { pc: 0x402.into(), opcode: Sub, arg: Some(1) });
+    }
+
+    #[test]
+    fn test_basic_ops() {
+        control_flow_opcode_test(vec![], Nop, 1025);
+        simple_opcode_test(vec![2], Nop, vec![2]);
+        predicate_opcode_test(Hlt, |_| { }, |cpu| { assert!(cpu.halted) })
+    }
+
+    #[test]
+    fn test_branching_jumping() {
+        control_flow_opcode_test(vec![1234], Jmp, 1234);
+        control_flow_opcode_test(vec![35], Jmpr, 1024 + 35);
+        control_flow_opcode_test(vec![to_word(-3)], Jmpr, 1024 - 3);
+        control_flow_opcode_test(vec![0, 35], Brnz, 1024 + 1);
+        control_flow_opcode_test(vec![17, 35], Brnz, 1024 + 35);
+        control_flow_opcode_test(vec![5, 35], Brz, 1024 + 1);
+        control_flow_opcode_test(vec![0, 35], Brz, 1024 + 35);
+    }
+
+    #[test]
+    fn test_memory() {
+        memory_opcode_test(vec![2048], vec![123], Load, vec![123], None);
+        memory_opcode_test(vec![2048], vec![0x12, 0x34, 0x56], Loadw, vec![0x123456], None);
+        memory_opcode_test(vec![100, 2048], vec![0x12, 0x34, 0x56], Store, vec![], Some(vec![100, 0x34, 0x56]));
+        memory_opcode_test(vec![0x112233, 2048], vec![0x12, 0x34, 0x56], Storew, vec![], Some(vec![0x33, 0x22, 0x11]));
+    }
+
+    #[test]
+    fn test_logic() {
         simple_opcode_test(vec![0b111100, 0b001111], And, vec![0b001100]);
         simple_opcode_test(vec![0b100, 0b001], Or, vec![0b101]);
         simple_opcode_test(vec![0b101, 0b011], Xor, vec![0b110]);
@@ -395,6 +1405,18 @@ mod tests {
         simple_opcode_test(vec![0b1100, 2], Rshift, vec![3]);
         simple_opcode_test(vec![0b1100, 2], Lshift, vec![0b110000]);
         simple_opcode_test(vec![0x800010, 2], Arshift, vec![0xe00004]);
+        simple_opcode_test(vec![0b1100, 40], Lshift, vec![0]);
+        simple_opcode_test(vec![0b1100, 40], Rshift, vec![0]);
+        simple_opcode_test(vec![0x400000, 40], Arshift, vec![0]);
+        simple_opcode_test(vec![0x800000, 0xffffff], Arshift, vec![0xffffff]);
+        simple_opcode_test(vec![0x812345, 0], Arshift, vec![0x812345]);
+        simple_opcode_test(vec![0x012345, 0], Arshift, vec![0x012345]);
+        simple_opcode_test(vec![0x123456, 4], Arshift, vec![0x012345]);
+        simple_opcode_test(vec![0xfffff0, 4], Arshift, vec![0xffffff]);
+        simple_opcode_test(vec![0x800000, 23], Arshift, vec![0xffffff]);
+        simple_opcode_test(vec![0x800000, 24], Arshift, vec![0xffffff]);
+        simple_opcode_test(vec![0x7fffff, 23], Arshift, vec![0]);
+        simple_opcode_test(vec![0x7fffff, 24], Arshift, vec![0]);
     }
 
     #[test]
@@ -402,11 +1424,31 @@ mod tests {
         call_stack_opcode_test(vec![5000], vec![], Call, vec![], vec![1025], 5000.into());
         call_stack_opcode_test(vec![], vec![5000], Ret, vec![], vec![], 5000.into());
         call_stack_opcode_test(vec![], vec![], Sdp, vec![1024, 256 + 6], vec![], 1025.into());
+        predicate_opcode_test(Setsdp,
+                              |cpu| {
+                                  cpu.push_data(900u32);
+                                  cpu.push_data(300u32)
+                              },
+                              |cpu| {
+                                  assert_eq!(cpu.sp, 900.into());
+                                  assert_eq!(cpu.dp, 300.into())
+                              });
+        // Crossing the stacks is legal too, with stack checks on or off
         predicate_opcode_test(Setsdp,
                               |cpu| {
                                   cpu.push_data(1000u32);
                                   cpu.push_data(2000u32)
                               },
+                              |cpu| {
+                                  assert_eq!(cpu.fault(), None);
+                                  assert_eq!((cpu.dp, cpu.sp), (2000.into(), 1000.into()))
+                              });
+        predicate_opcode_test(Setsdp,
+                              |cpu| {
+                                  cpu.set_stack_checks(false);
+                                  cpu.push_data(1000u32);
+                                  cpu.push_data(2000u32)
+                              },
                               |cpu| {
                                   assert_eq!(cpu.sp, 1000.into());
                                   assert_eq!(cpu.dp, 2000.into())
@@ -414,13 +1456,19 @@ mod tests {
         call_stack_opcode_test(vec![123], vec![], Pushr, vec![], vec![123], 1025.into());
         call_stack_opcode_test(vec![], vec![123], Popr, vec![123], vec![], 1025.into());
         call_stack_opcode_test(vec![], vec![123], Peekr, vec![123], vec![123], 1025.into());
+
+        // Returning with nothing to return to pops whatever is above the stack
+        predicate_opcode_test(Ret, |_| {}, |cpu| {
+            assert_eq!(cpu.fault(), None);
+            assert_eq!(cpu.sp, 1027.into())
+        });
     }
 
     #[test]
     fn test_cpu_new() {
         let cpu = CPU::new(Memory::default());
         assert_eq!(cpu.pc, 1024.into());
-        assert_eq!(cpu.halted, true);
+        assert!(cpu.halted);
     }
 
     #[test]
@@ -455,6 +1503,736 @@ mod tests {
         assert_eq!(cpu.sp, 1024.into());
     }
 
+    #[test]
+    fn test_register_accessors() {
+        let mut cpu = CPU::new(Memory::default());
+        cpu.memory.poke_slice(0x600.into(), &[0x01, 0x2a, Hlt as u8 * 4]); // push 42, hlt
+        assert!(cpu.is_halted());
+        cpu.set_pc(0x600.into());
+        cpu.resume();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.pc(), 0x600.into());
+
+        assert_eq!(cpu.step(), Ok(State::Running));
+        assert_eq!(cpu.pc(), 0x602.into());
+        assert_eq!(cpu.data_stack(), vec![42.into()]);
+        assert_eq!((cpu.dp(), cpu.sp()), (259.into(), 1024.into()));
+
+        cpu.halt();
+        assert_eq!(cpu.run(100), (0, StopReason::Halted));
+        cpu.resume();
+        assert_eq!(cpu.run(100), (1, StopReason::Halted));
+    }
+
+    #[test]
+    fn test_inspection() {
+        let mut cpu = CPU::new(Memory::default());
+        assert_eq!(cpu.data_stack(), vec![]);
+        assert_eq!(cpu.call_stack(), vec![]);
+
+        cpu.push_data(37u32);
+        cpu.push_data(45u32);
+        cpu.push_call(12u32);
+        cpu.push_call(34u32);
+        assert_eq!(cpu.data_stack(), vec![37.into(), 45.into()]);
+        assert_eq!(cpu.call_stack(), vec![12.into(), 34.into()]);
+
+        let regs = cpu.registers();
+        assert_eq!((regs.pc, regs.iv), (1024.into(), 1024.into()));
+        assert_eq!((regs.dp, regs.sp), ((256 + 6).into(), (1024 - 6).into()));
+        assert!(regs.halted && !regs.int_enabled && !regs.waiting);
+    }
+
+    fn countdown_cpu() -> CPU {
+        let mut cpu = CPU::new(Memory::default());
+        cpu.memory.poke_u32(0x400, 0x01); // nop 5 (push the counter)
+        cpu.memory.poke_u32(0x401, 0x05);
+        cpu.memory.poke_u32(0x402, 0x09); // sub 1
+        cpu.memory.poke_u32(0x403, 0x01);
+        cpu.memory.poke_u32(0x404, 19 << 2); // dup
+        cpu.memory.poke_u32(0x405, (28 << 2) | 3); // brnz -3
+        cpu.memory.poke24_u32(0x406, to_word(-3));
+        cpu.memory.poke_u32(0x409, 29 << 2); // hlt
+        cpu.halted = false;
+        cpu
+    }
+
+    #[test]
+    fn test_cpu_step() {
+        let mut cpu = countdown_cpu();
+        assert_eq!(cpu.step(), Ok(State::Running));
+        assert_eq!(cpu.pc, 0x402.into());
+        assert_eq!(cpu.get_stack(), vec![5]);
+
+        cpu.memory.poke_u32(0x402, 0xfc);
+        assert_eq!(cpu.step(), Err(ExecError::InvalidOpcode(0x3f)));
+    }
+
+    #[test]
+    fn test_cpu_run() {
+        // One push, five trips around the sub/dup/brnz loop, then the hlt
+        let mut cpu = countdown_cpu();
+        assert_eq!(cpu.run(1000), (1 + 5 * 3 + 1, StopReason::Halted));
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x40a.into());
+        assert_eq!(cpu.get_stack(), vec![0]);
+
+        // Halted CPUs don't run at all
+        assert_eq!(cpu.run(1000), (0, StopReason::Halted));
+    }
+
+    #[test]
+    fn test_cpu_run_budget() {
+        let mut cpu = countdown_cpu();
+        assert_eq!(cpu.run(0), (0, StopReason::CyclesExhausted));
+        assert_eq!(cpu.pc, 0x400.into());
+
+        assert_eq!(cpu.run(4), (4, StopReason::CyclesExhausted));
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc, 0x402.into());
+        assert_eq!(cpu.get_stack(), vec![4]);
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        // Each loadw costs three cycles and the pop after it one
+        let mut cpu = CPU::boot(&crate::asm::assemble(&"loadw\npop\n".repeat(10)).unwrap()).unwrap();
+        cpu.set_stack_checks(false);
+        assert_eq!(cpu.run_cycles(8), (4, StopReason::CyclesExhausted));
+        assert_eq!(cpu.cycle_count(), 8);
+        assert_eq!(cpu.run_cycles(2), (1, StopReason::CyclesExhausted)); // One over
+        assert_eq!(cpu.cycle_count(), 11);
+        assert_eq!(cpu.run_cycles(0), (0, StopReason::CyclesExhausted));
+    }
+
+    #[test]
+    fn test_cpu_run_fault() {
+        let mut cpu = CPU::boot(&crate::asm::assemble("push 5\ndiv 0\nhlt").unwrap()).unwrap();
+        assert_eq!(cpu.run(100), (2, StopReason::Fault(ExecError::DivByZero)));
+        assert_eq!(cpu.run(100), (0, StopReason::Halted));
+
+        // Opcode 63 doesn't exist, so it doesn't count as run
+        let mut cpu = CPU::boot(&[0, 63 << 2]).unwrap();
+        assert_eq!(cpu.run(100), (1, StopReason::Fault(ExecError::InvalidOpcode(63))));
+        assert_eq!(cpu.state(), State::Halted);
+        assert_eq!(cpu.fault(), Some(ExecError::InvalidOpcode(63)));
+        assert_eq!(cpu.pc, 0x401.into()); // Left on the opcode that didn't decode
+        assert_eq!(cpu.run(100), (0, StopReason::Halted)); // Rather than fault again
+
+        // A faulted CPU stays halted through an interrupt, until it's resumed
+        cpu.iv = 0x800.into();
+        cpu.int_enabled = true;
+        cpu.vblank();
+        assert_eq!(cpu.state(), State::Halted);
+        assert_eq!(cpu.fault(), Some(ExecError::InvalidOpcode(63)));
+        assert_eq!(cpu.pc, 0x401.into());
+        cpu.resume();
+        assert_eq!(cpu.fault(), None);
+        cpu.vblank();
+        assert_eq!((cpu.state(), cpu.pc), (State::Running, 0x800.into()));
+    }
+
+    #[test]
+    fn test_cpu_waitv() {
+        let mut cpu = CPU::new(Memory::default());
+        cpu.memory.poke_u32(0x400, 43 << 2); // waitv
+        cpu.memory.poke_u32(0x401, 0x01); // nop 7
+        cpu.memory.poke_u32(0x402, 0x07);
+        cpu.memory.poke_u32(0x403, 29 << 2); // hlt
+        cpu.halted = false;
+
+        assert_eq!(cpu.run(100), (1, StopReason::WaitingForVblank));
+        assert_eq!(cpu.state(), State::WaitingForVblank);
+        assert_eq!(cpu.pc, 0x401.into());
+
+        // Nothing happens until a frame is drawn
+        assert_eq!(cpu.run(100), (0, StopReason::WaitingForVblank));
+        assert_eq!(cpu.get_stack(), vec![]);
+
+        cpu.vblank();
+        assert_eq!(cpu.run(100), (2, StopReason::Halted));
+        assert_eq!(cpu.state(), State::Halted);
+        assert_eq!(cpu.get_stack(), vec![7]);
+    }
+
+    #[test]
+    fn test_cpu_interrupt() {
+        let mut cpu = CPU::new(Memory::default());
+        cpu.halted = false;
+        cpu.pc = 0x500.into();
+        cpu.iv = 0x800.into();
+        cpu.int_enabled = true;
+
+        cpu.interrupt();
+        assert_eq!(cpu.pc, 0x800.into());
+        assert_eq!(cpu.get_call(), vec![0x500]);
+        assert!(!cpu.int_enabled);
+
+        // A second interrupt before the handler re-enables them is dropped
+        cpu.interrupt();
+        assert_eq!(cpu.pc, 0x800.into());
+        assert_eq!(cpu.get_call(), vec![0x500]);
+    }
+
+    #[test]
+    fn test_interrupt_without_room() {
+        let mut cpu = CPU::new(Memory::default());
+        cpu.resume();
+        cpu.pc = 0x500.into();
+        cpu.iv = 0x800.into();
+        cpu.int_enabled = true;
+        cpu.set_max_call_depth(Some(0));
+
+        cpu.interrupt();
+        assert_eq!(cpu.fault(), Some(ExecError::CallDepthExceeded));
+        assert_eq!(cpu.state(), State::Halted);
+        assert_eq!(cpu.pc, 0x500.into()); // Never went to the handler
+        assert_eq!(cpu.call_depth(), 0);
+
+        // With room again, a later interrupt still doesn't wake it from the fault
+        cpu.set_max_call_depth(None);
+        cpu.vblank();
+        assert_eq!((cpu.state(), cpu.pc, cpu.call_depth()), (State::Halted, 0x500.into(), 0));
+        assert_eq!(cpu.fault(), Some(ExecError::CallDepthExceeded));
+
+        // The same for the stacks meeting
+        let mut cpu = CPU::new(Memory::default());
+        cpu.resume();
+        cpu.pc = 0x500.into();
+        cpu.int_enabled = true;
+        cpu.sp = 259.into();
+        cpu.dp = 259.into();
+        cpu.interrupt();
+        assert_eq!(cpu.fault(), Some(ExecError::StackOverflow));
+        assert_eq!((cpu.state(), cpu.pc, cpu.sp), (State::Halted, 0x500.into(), 259.into()));
+    }
+
+    #[test]
+    fn test_vblank_interrupt() {
+        let mut cpu = CPU::new(Memory::default());
+        let program = crate::asm::assemble("push 0x500\nsetiv\ninton\npush -4\njmpr").unwrap(); // Spins at 0x405
+        cpu.load_program(0x400.into(), &program);
+        cpu.memory.poke(0x500.into(), Hlt as u8 * 4);
+        assert_eq!(cpu.run(5), (5, StopReason::CyclesExhausted));
+        assert_eq!(cpu.pc, 0x405.into());
+
+        cpu.vblank();
+        assert_eq!(cpu.pc, 0x500.into());
+        assert_eq!(cpu.get_call(), vec![0x405]);
+        assert_eq!(cpu.run(100), (1, StopReason::Halted));
+        assert_eq!(cpu.state(), State::Halted);
+    }
+
+    #[test]
+    fn test_cpu_interrupt_wakes() {
+        let mut cpu = CPU::new(Memory::default());
+        cpu.iv = 0x800.into();
+        cpu.interrupt();
+        assert!(cpu.halted); // Interrupts start out disabled
+
+        cpu.int_enabled = true;
+        cpu.interrupt();
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc, 0x800.into());
+        assert_eq!(cpu.get_call(), vec![1024]);
+    }
+
+    #[test]
+    fn test_fuzz_never_panics() {
+        // Random memory, random registers, and whatever happens happens: bad
+        // instructions may return errors, but nothing is allowed to panic.
+        // Half the runs keep stack checks on and the stacks where they start,
+        // so only the program itself can move them past the debug assertion.
+        let mut rng = StdRng::seed_from_u64(0x7e57);
+        for run in 0..40 {
+            let mut cpu = CPU::new_seeded(Memory::randomized(&mut StdRng::seed_from_u64(rng.gen())), rng.gen());
+            cpu.halted = false;
+            cpu.pc = rng.gen::<u32>().into();
+            if run % 2 == 0 {
+                cpu.dp = rng.gen::<u32>().into();
+                cpu.sp = rng.gen::<u32>().into();
+                cpu.set_stack_checks(false);
+            }
+            for _ in 0..5000 {
+                if cpu.step().is_err() {
+                    cpu.pc = rng.gen::<u32>().into()
+                }
+            }
+        }
+    }
+
+    // Pushes the data stack up past the call stack without going through
+    // any instruction, the way an emulator bug might
+    fn corrupt_stacks(cpu: &mut CPU) {
+        cpu.dp = cpu.sp + 3
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "stack pointers out of order")]
+    fn test_stack_check() {
+        let mut cpu = CPU::new(Memory::default());
+        assert!(cpu.stacks_in_order());
+        corrupt_stacks(&mut cpu);
+        assert!(!cpu.stacks_in_order());
+        cpu.step().unwrap();
+    }
+
+    #[test]
+    fn test_stack_check_guest_moves() {
+        // Moving the call stack out of its space, then using the stacks, is
+        // the program's business, until it puts them back in order
+        let program = "push 2000\npush 300\nsetsdp\npush 7\npushr\npush 900\npush 300\nsetsdp\nhlt";
+        let mut cpu = CPU::boot(&crate::asm::assemble(program).unwrap()).unwrap();
+        assert_eq!(cpu.run(5), (5, StopReason::CyclesExhausted));
+        assert!(cpu.stacks_moved);
+        assert_eq!((cpu.dp, cpu.sp), (300.into(), 1997.into()));
+        assert_eq!(cpu.run(100), (4, StopReason::Halted));
+        assert_eq!(cpu.fault(), None);
+        assert!(!cpu.stacks_moved);
+
+        // So is returning with nothing on the call stack
+        let mut cpu = CPU::boot(&[Ret as u8 * 4]).unwrap();
+        assert_eq!(cpu.step(), Ok(State::Running));
+        assert_eq!(cpu.sp, 1027.into());
+    }
+
+    #[test]
+    fn test_stack_check_disabled() {
+        let mut cpu = CPU::new(Memory::default());
+        cpu.set_stack_checks(false);
+        corrupt_stacks(&mut cpu);
+        assert_eq!(cpu.step(), Ok(State::Halted));
+    }
+
+    // A small device the test can still see into after it's moved into a bus
+    #[derive(Clone)]
+    struct SharedRam(Rc<RefCell<Vec<u8>>>);
+    impl PeekPoke for SharedRam {
+        fn peek(&self, addr: Word) -> u8 {
+            let ram = self.0.borrow();
+            ram[u32::from(addr) as usize % ram.len()]
+        }
+        fn poke(&mut self, addr: Word, val: u8) {
+            let mut ram = self.0.borrow_mut();
+            let len = ram.len();
+            ram[u32::from(addr) as usize % len] = val
+        }
+    }
+
+    #[test]
+    fn test_cpu_over_bus() {
+        let device = SharedRam(Rc::new(RefCell::new(vec![0u8; 16])));
+        let mut cpu = CPU::new(Bus::new(0x2000, 0x2010, device.clone(), Memory::default()));
+
+        cpu.push_data(0x123456u32);
+        cpu.push_data(0x2004u32);
+        cpu.execute(Instruction { opcode: Storew, arg: None, length: 1 });
+        assert_eq!(device.0.borrow()[4..7], [0x56, 0x34, 0x12]);
+
+        cpu.push_data(7u32);
+        cpu.push_data(0x3000u32);
+        cpu.execute(Instruction { opcode: Store, arg: None, length: 1 });
+        assert_eq!(device.0.borrow().iter().filter(|&&b| b != 0).count(), 3); // Didn't reach the device
+
+        cpu.push_data(0x2005u32);
+        cpu.execute(Instruction { opcode: Load, arg: None, length: 1 });
+        cpu.push_data(0x3000u32);
+        cpu.execute(Instruction { opcode: Load, arg: None, length: 1 });
+        assert_eq!(cpu.get_stack(), vec![0x34, 7]);
+    }
+
+    // Counts how many times it's been ticked
+    struct Counter(u8);
+    impl PeekPoke for Counter {
+        fn peek(&self, _addr: Word) -> u8 { self.0 }
+        fn poke(&mut self, _addr: Word, _val: u8) {}
+    }
+    impl Device for Counter {
+        fn tick(&mut self) { self.0 += 1 }
+        fn reset(&mut self) { self.0 = 0 }
+    }
+
+    fn counter_cpu() -> CPU<Bus<Counter, Memory>> {
+        let mut cpu = CPU::new(Bus::new(0x2000, 0x2001, Counter(0), Memory::default()));
+        cpu.memory.poke_u32(0x400, 0); // nop
+        cpu.memory.poke_u32(0x401, 0); // nop
+        cpu.memory.poke_u32(0x402, 0); // nop
+        cpu.memory.poke_u32(0x403, (30 << 2) | 2); // load 0x2000
+        cpu.memory.poke_u32(0x404, 0x00);
+        cpu.memory.poke_u32(0x405, 0x20);
+        cpu.memory.poke_u32(0x406, 29 << 2); // hlt
+        cpu.halted = false;
+        cpu
+    }
+
+    #[test]
+    fn test_reset_all() {
+        let mut cpu = counter_cpu();
+        cpu.memory.tick();
+        cpu.reset();
+        assert_eq!(cpu.memory.peek_u32(0x2000), 1); // Registers only
+
+        cpu.resume();
+        cpu.reset_all();
+        assert_eq!(cpu.memory.peek_u32(0x2000), 0);
+        assert_eq!(cpu.state(), State::Halted);
+        assert_eq!(cpu.pc, 1024.into());
+    }
+
+    #[test]
+    fn test_device_ticks() {
+        // Three nops have each ticked the counter by the time the load runs
+        let mut cpu = counter_cpu();
+        cpu.set_device_ticks(true);
+        assert_eq!(cpu.run(100), (5, StopReason::Halted));
+        assert_eq!(cpu.get_stack(), vec![3]);
+        assert_eq!(cpu.memory.peek_u32(0x2000), 5);
+
+        let mut cpu = counter_cpu();
+        assert_eq!(cpu.run(100), (5, StopReason::Halted));
+        assert_eq!(cpu.get_stack(), vec![0]);
+    }
+
+    // Requests an interrupt every `period` ticks, and counts how many times
+    struct Timer { period: u8, count: u8, fired: u8, pending: bool }
+    impl PeekPoke for Timer {
+        fn peek(&self, _addr: Word) -> u8 { self.fired }
+        fn poke(&mut self, _addr: Word, _val: u8) {}
+    }
+    impl Device for Timer {
+        fn tick(&mut self) {
+            self.count += 1;
+            if self.count == self.period {
+                self.count = 0;
+                self.fired += 1;
+                self.pending = true
+            }
+        }
+        fn reset(&mut self) {}
+        fn take_interrupt(&mut self) -> bool { std::mem::take(&mut self.pending) }
+    }
+
+    #[test]
+    fn test_advance_cycles() {
+        let timer = Timer { period: 10, count: 0, fired: 0, pending: false };
+        let mut cpu = CPU::new(Bus::new(0x2000, 0x2001, timer, Memory::default()));
+        cpu.iv = 0x800.into();
+        cpu.int_enabled = true;
+
+        // The handler never runs to re-enable interrupts, so only the first is taken
+        assert_eq!(cpu.advance_cycles(35), 1);
+        assert_eq!(cpu.memory.peek_u32(0x2000), 3);
+        assert_eq!(cpu.pc, 0x800.into());
+        assert!(!cpu.halted);
+
+        // Five more cycles finishes a period; re-enabling like a handler would takes it
+        cpu.int_enabled = true;
+        assert_eq!(cpu.advance_cycles(4), 0);
+        assert_eq!(cpu.advance_cycles(1), 1);
+        cpu.int_enabled = true;
+        assert_eq!(cpu.advance_cycles(20), 1);
+        assert_eq!(cpu.memory.peek_u32(0x2000), 6);
+        assert_eq!(cpu.get_call(), vec![1024, 0x800, 0x800]);
+        assert_eq!(cpu.cycle_count(), 60);
+    }
+
+    #[test]
+    fn test_advance_cycles_full_call_stack() {
+        let timer = Timer { period: 10, count: 0, fired: 0, pending: false };
+        let mut cpu = CPU::new(Bus::new(0x2000, 0x2001, timer, Memory::default()));
+        cpu.iv = 0x800.into();
+        cpu.int_enabled = true;
+        cpu.dp = cpu.sp; // No room left for a return address
+
+        // The timer fires three times, but none of them gets delivered
+        assert_eq!(cpu.advance_cycles(35), 0);
+        assert_eq!(cpu.memory.peek_u32(0x2000), 3);
+        assert_eq!(cpu.fault(), Some(ExecError::StackOverflow));
+        assert_eq!((cpu.pc, cpu.call_depth()), (0x400.into(), 0));
+        assert!(!cpu.interrupt());
+    }
+
+    #[test]
+    fn test_device_interrupts_during_run() {
+        let timer = Timer { period: 2, count: 0, fired: 0, pending: false };
+        let mut cpu = CPU::new(Bus::new(0x2000, 0x2001, timer, Memory::default()));
+        cpu.memory.poke_u32(0x400, 34 << 2); // inton
+        cpu.memory.poke_u32(0x401, 0); // nop
+        cpu.memory.poke_u32(0x402, 29 << 2); // hlt
+        cpu.memory.poke_u32(0x800, 29 << 2); // hlt, in the handler
+        cpu.iv = 0x800.into();
+        cpu.halted = false;
+        cpu.set_device_ticks(true);
+
+        assert_eq!(cpu.run(100), (3, StopReason::Halted));
+        assert_eq!(cpu.pc, 0x801.into());
+        assert_eq!(cpu.get_call(), vec![0x402]);
+    }
+
+    #[test]
+    fn test_load_program() {
+        let mut cpu = CPU::new(Memory::default());
+        let program = [0x01, 0x02, 0x05, 0x03, 29 << 2]; // push 2, add 3, hlt
+        assert_eq!(cpu.load_program(0x600.into(), &program), 5);
+        assert_eq!(cpu.pc, 0x600.into());
+        assert!(!cpu.halted);
+
+        assert_eq!(cpu.run(100), (3, StopReason::Halted));
+        assert_eq!(cpu.get_stack(), vec![5]);
+    }
+
+    // Collects warnings logged on the current thread, so tests running in
+    // parallel don't see each other's
+    struct CapturingLogger;
+    thread_local! {
+        static WARNINGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool { metadata.level() <= log::Level::Warn }
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                WARNINGS.with(|w| w.borrow_mut().push(record.args().to_string()))
+            }
+        }
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn test_fault_logs_warning() {
+        static LOGGER: CapturingLogger = CapturingLogger;
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Warn)
+        });
+
+        let mut cpu = CPU::new(Memory::default());
+        cpu.memory.poke_u32(0x400, 0xfc);
+        assert_eq!(cpu.step(), Err(ExecError::InvalidOpcode(0x3f)));
+        WARNINGS.with(|w| assert_eq!(*w.borrow(), vec!["Invalid opcode 0x3f at 000400"]));
+    }
+
+    #[test]
+    fn test_data_stack_underflow() {
+        let mut cpu = CPU::new(Memory::default());
+        cpu.memory.poke24(253.into(), 0x123456); // Just below the data stack
+        cpu.memory.poke_slice(1024.into(), &[Pop as u8 * 4, Nop as u8 * 4]);
+        cpu.halted = false;
+        assert_eq!(cpu.step(), Err(ExecError::StackUnderflow));
+        assert!(cpu.halted);
+        assert_eq!(cpu.fault(), Some(ExecError::StackUnderflow));
+        assert_eq!(cpu.dp, 256.into());
+
+        // An add with one operand pops a 0 for the missing one, rather than
+        // the 0x123456 lurking below the stack
+        cpu.reset();
+        cpu.push_data(5u32);
+        cpu.memory.poke(1024.into(), Add as u8 * 4);
+        cpu.halted = false;
+        assert_eq!(cpu.step(), Err(ExecError::StackUnderflow));
+        assert_eq!(cpu.get_stack(), vec![5]);
+        assert_eq!(cpu.fault(), Some(ExecError::StackUnderflow));
+
+        cpu.reset();
+        assert_eq!(cpu.fault(), None);
+        cpu.memory.poke(1024.into(), Dup as u8 * 4);
+        cpu.halted = false;
+        assert_eq!(cpu.step(), Err(ExecError::StackUnderflow));
+        assert_eq!(cpu.fault(), Some(ExecError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_exec_errors() {
+        // Runs all but the last line, then steps that
+        let step = |source: &str| {
+            let mut cpu = CPU::new(Memory::default());
+            cpu.load_program(1024.into(), &crate::asm::assemble(source).unwrap());
+            cpu.run(source.lines().count() - 1);
+            (cpu.step(), cpu)
+        };
+
+        let (result, cpu) = step("push 7\ndiv 0");
+        assert_eq!(result, Err(ExecError::DivByZero));
+        assert_eq!(cpu.get_stack(), vec![0xffffff]); // Still finished
+        assert_eq!(cpu.fault(), Some(ExecError::DivByZero));
+        assert!(cpu.halted);
+        assert_eq!(step("push 7\nmod 0").0, Err(ExecError::DivByZero));
+        assert_eq!(step("pop").0, Err(ExecError::StackUnderflow));
+        assert_eq!(step("push 5\npick 1").0, Err(ExecError::PickOutOfRange));
+
+        let mut cpu = CPU::new(Memory::default());
+        cpu.load_program(1024.into(), &[0xfc]);
+        assert_eq!(cpu.step(), Err(ExecError::InvalidOpcode(0x3f)));
+        assert_eq!(cpu.step().unwrap_err().to_string(), "Invalid opcode 0x3f");
+
+        let mut cpu = CPU::new(Memory::default());
+        cpu.load_program(1024.into(), &crate::asm::assemble("push 1").unwrap());
+        cpu.sp = 259.into();
+        cpu.dp = 259.into();
+        assert_eq!(cpu.step(), Err(ExecError::StackOverflow));
+    }
+
+    #[test]
+    fn test_pick_depth() {
+        let mut cpu = CPU::new(Memory::default());
+        cpu.memory.poke24(253.into(), 0xabcdef); // Just below the data stack
+        cpu.load_program(1024.into(), &crate::asm::assemble("push 10\npush 20\npick 1\npick 3").unwrap());
+        assert_eq!(cpu.run(3), (3, StopReason::CyclesExhausted));
+        assert_eq!(cpu.get_stack(), vec![10, 20, 10]); // The deepest pick that fits
+
+        assert_eq!(cpu.step(), Err(ExecError::PickOutOfRange));
+        assert_eq!(cpu.get_stack(), vec![10, 20, 10, 0]);
+        assert!(cpu.halted);
+
+        // Unchecked, it reads whatever's there
+        let mut cpu = CPU::new(Memory::default());
+        cpu.set_stack_checks(false);
+        cpu.memory.poke24(253.into(), 0xabcdef);
+        cpu.load_program(1024.into(), &crate::asm::assemble("push 10\npick 1").unwrap());
+        assert_eq!(cpu.run(2), (2, StopReason::CyclesExhausted));
+        assert_eq!(cpu.get_stack(), vec![10, 0xabcdef]);
+    }
+
+    #[test]
+    fn test_call_depth() {
+        // Each call goes to the next, five deep, then halts
+        let mut cpu = CPU::boot(&crate::asm::assemble("push 1028\ncall\npush 1032\ncall\npush 1036\ncall\npush 1040\ncall\npush 1044\ncall\nhlt").unwrap()).unwrap();
+        assert_eq!(cpu.call_depth(), 0);
+        assert_eq!(cpu.run(100), (11, StopReason::Halted));
+        assert_eq!(cpu.call_depth(), 5);
+        assert_eq!(cpu.fault(), None);
+
+        // Recursion stops at the limit instead of running into the data stack
+        let mut cpu = CPU::boot(&crate::asm::assemble("push 1024\ncall").unwrap()).unwrap();
+        cpu.set_max_call_depth(Some(10));
+        assert_eq!(cpu.run(10000), (22, StopReason::Fault(ExecError::CallDepthExceeded))); // Ten calls, then the eleventh faults
+        assert_eq!(cpu.call_depth(), 10);
+        assert_eq!(cpu.fault(), Some(ExecError::CallDepthExceeded));
+    }
+
+    #[test]
+    fn test_stack_collision() {
+        // Pushes forever, growing the data stack up into the call stack
+        let mut cpu = CPU::new(Memory::default());
+        cpu.memory.poke24(1024.into(), 0xabcdef); // Where the data stack would spill over
+        cpu.load_program(1030.into(), &crate::asm::assemble("push 7\npush -6\njmpr").unwrap());
+        cpu.sp = 1024.into();
+        assert_eq!(cpu.run(10000), (767, StopReason::Fault(ExecError::StackOverflow))); // The 256th loop fills the stack and faults on its second push
+        assert_eq!(cpu.pc, 1036.into()); // Past the push that faulted
+        assert_eq!(cpu.fault(), Some(ExecError::StackOverflow));
+        assert_eq!(cpu.dp, 1024.into());
+        assert_eq!(cpu.memory.peek24(1024.into()), 0xabcdef);
+
+        // Recurses forever, growing the call stack down into the data stack
+        let mut cpu = CPU::new(Memory::default());
+        cpu.memory.poke24(253.into(), 0xabcdef);
+        cpu.push_data(0x123456u32);
+        cpu.load_program(1024.into(), &crate::asm::assemble("push 1024\ncall").unwrap());
+        assert_eq!(cpu.run(10000), (511, StopReason::Fault(ExecError::StackOverflow))); // 255 calls fill the stack, then the push faults
+        assert_eq!(cpu.pc, 1027.into());
+        assert_eq!(cpu.fault(), Some(ExecError::StackOverflow));
+        assert_eq!(cpu.sp, 259.into());
+        assert_eq!(cpu.get_stack(), vec![0x123456]);
+        assert_eq!(cpu.memory.peek24(253.into()), 0xabcdef);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        use crate::address::MEM_SIZE;
+        let mut cpu = countdown_cpu();
+        assert_eq!(cpu.run(7), (7, StopReason::CyclesExhausted));
+        let snapshot = cpu.snapshot();
+        let (pc, stack) = (cpu.pc, cpu.get_stack());
+
+        let bytes = bincode::serialize(&snapshot).unwrap();
+        assert!(bytes.len() < MEM_SIZE as usize + 64);
+        let restored: Snapshot = bincode::deserialize(&bytes).unwrap();
+
+        cpu.run(1000);
+        assert_eq!(cpu.state(), State::Halted);
+        cpu.restore(restored);
+        assert_eq!((cpu.pc, cpu.get_stack()), (pc, stack));
+        assert_eq!(cpu.state(), State::Running);
+        assert!((0..MEM_SIZE).all(|a| cpu.memory.peek_u32(a) == snapshot.memory.peek_u32(a)));
+
+        // And it carries on from there just the same
+        let (n, stop) = countdown_cpu().run(1000);
+        assert_eq!(cpu.run(1000), (n - 7, stop));
+        assert!(bincode::deserialize::<Snapshot>(&bytes[..100]).is_err());
+    }
+
+    #[test]
+    fn test_cycle_count() {
+        let mut cpu = countdown_cpu();
+        assert_eq!(cpu.cycle_count(), 0);
+        cpu.run(1000);
+        // Push 5, five trips round the loop of sub 1, dup, and brnz -3, and hlt
+        assert_eq!(cpu.cycle_count(), 2 + 5 * (2 + 1 + 5) + 1);
+
+        cpu.step().unwrap(); // Stepping a halted CPU still counts
+        assert_eq!(cpu.cycle_count(), 44);
+        cpu.reset();
+        assert_eq!(cpu.cycle_count(), 0);
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut cpu = countdown_cpu();
+        cpu.add_breakpoint(0x404.into()); // The dup in the middle of the loop
+        assert_eq!(cpu.run(1000), (2, StopReason::Breakpoint(0x404.into())));
+        assert_eq!(cpu.get_stack(), vec![4]);
+
+        // Running again goes once round the loop, back to the same place
+        assert_eq!(cpu.run(1000), (3, StopReason::Breakpoint(0x404.into())));
+        assert_eq!(cpu.get_stack(), vec![3]);
+
+        // Stepping doesn't care about breakpoints
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x405.into());
+
+        cpu.remove_breakpoint(0x404.into());
+        assert_eq!(cpu.run(1000), (1 + 3 * 3 + 1, StopReason::Halted));
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut cpu = CPU::new(Memory::default());
+        let program = crate::asm::assemble("push 7\npush 0x2000\nstore\npush 0x123456\npush 0x1ffe\nstorew\nhlt").unwrap();
+        cpu.load_program(0x400.into(), &program);
+        cpu.memory.poke(0x2000.into(), 0x55);
+        cpu.add_watchpoint(0x2000.into());
+
+        let stop = StopReason::Watchpoint { addr: 0x2000.into(), old: 0x55, new: 7 };
+        assert_eq!(cpu.run(100), (3, stop));
+        assert_eq!(cpu.memory.peek_u32(0x2000), 7); // The store did happen
+
+        // A word store that only overlaps the watched byte still counts
+        let stop = StopReason::Watchpoint { addr: 0x1ffe.into(), old: 0x070000, new: 0x123456 };
+        assert_eq!(cpu.run(100), (3, stop));
+
+        cpu.remove_watchpoint(0x2000.into());
+        cpu.reset();
+        cpu.load_program(0x400.into(), &program);
+        assert_eq!(cpu.run(100), (7, StopReason::Halted));
+    }
+
+    #[test]
+    fn test_storew_whole_word() {
+        use crate::bus::Tracing;
+        let program = crate::asm::assemble("push 0x123456\npush 0x2000\nstorew\npush 0x78\npush 0x2003\nstore\nhlt").unwrap();
+        let mut cpu = CPU::new(Tracing::new(Memory::default(), Vec::new()));
+        cpu.memory.poke_slice(ENTRY_POINT.into(), &program);
+        cpu.resume();
+        assert_eq!(cpu.run(100), (7, StopReason::Halted));
+
+        // Leaving out the stack pushes, down in page 0, the word goes out in one write
+        let (_, out) = cpu.memory.into_parts();
+        let out = String::from_utf8(out).unwrap();
+        let stores: Vec<_> = out.lines().filter(|line| line.starts_with("poke") && !line.contains(" 000")).collect();
+        assert_eq!(stores, vec!["poke24 002000 <- 123456", "poke 002003 <- 78"]);
+    }
+
     #[test]
     fn test_cpu_fetch() {
         let mut cpu = CPU::new(Memory::default());
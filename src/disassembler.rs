@@ -0,0 +1,77 @@
+use crate::address::Word;
+use crate::cpu::decode;
+use crate::memory::PeekPoke;
+
+/// Disassembles `count` instructions starting at `start`, returning each
+/// one's address and text, like `(1024, "add 0x123456")`. A byte that isn't
+/// a valid opcode comes out as `.byte 0xNN` and disassembly carries on with
+/// the next byte.
+pub fn disassemble<P: PeekPoke>(machine: &P, start: Word, count: usize) -> Vec<(Word, String)> {
+    let mut lines = Vec::with_capacity(count);
+    let mut addr = start;
+    for _ in 0..count {
+        let (text, next, _) = disassemble_one(machine, addr);
+        lines.push((addr, text));
+        addr = next
+    }
+    lines
+}
+
+/// Disassembles the one instruction at `addr`, returning its text, the
+/// address of the instruction after it, and its length in bytes. An invalid
+/// opcode is a one-byte `.byte 0xNN`.
+pub fn disassemble_one<P: PeekPoke>(machine: &P, addr: Word) -> (String, Word, u8) {
+    match decode(machine, addr) {
+        Ok(instruction) => {
+            let text = match instruction.arg {
+                Some(arg) => format!("{} {:#x}", instruction.opcode, arg),
+                None => instruction.opcode.to_string()
+            };
+            (text, addr + instruction.length as i32, instruction.length)
+        }
+        Err(_) => (format!(".byte {:#04x}", machine.peek(addr)), addr + 1, 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_disassemble() {
+        let mut mem = Memory::default();
+        mem.poke_u32(0x400, 0x01); // nop 1 arg
+        mem.poke_u32(0x401, 0x02); // 2
+        mem.poke_u32(0x402, 0x07); // add 3 arg
+        mem.poke24_u32(0x403, 0x123456); // 3-byte arg
+        mem.poke_u32(0x406, 29 << 2); // hlt
+        mem.poke_u32(0x407, 0xfc); // gibberish
+
+        let lines = disassemble(&mem, 0x400.into(), 5);
+        assert_eq!(lines, vec![
+            (0x400.into(), "nop 0x2".to_string()),
+            (0x402.into(), "add 0x123456".to_string()),
+            (0x406.into(), "hlt".to_string()),
+            (0x407.into(), ".byte 0xfc".to_string()),
+            (0x408.into(), "nop".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_disassemble_one() {
+        let mut mem = Memory::default();
+        mem.poke_u32(0x400, 0x01); // nop 1 arg
+        mem.poke_u32(0x401, 0x02); // 2
+        mem.poke_u32(0x402, 0x07); // add 3 arg
+        mem.poke24_u32(0x403, 0x123456); // 3-byte arg
+        mem.poke_u32(0x406, 29 << 2); // hlt
+        mem.poke_u32(0x407, 0xfc); // gibberish
+
+        assert_eq!(disassemble_one(&mem, 0x400.into()), ("nop 0x2".to_string(), 0x402.into(), 2));
+        assert_eq!(disassemble_one(&mem, 0x402.into()), ("add 0x123456".to_string(), 0x406.into(), 4));
+        assert_eq!(disassemble_one(&mem, 0x406.into()), ("hlt".to_string(), 0x407.into(), 1));
+        assert_eq!(disassemble_one(&mem, 0x407.into()), (".byte 0xfc".to_string(), 0x408.into(), 1));
+        assert_eq!(disassemble_one(&mem, 0xffffff.into()), ("nop".to_string(), 0.into(), 1)); // Wraps
+    }
+}
@@ -0,0 +1,885 @@
+use crate::address::Word;
+use crate::bus::Device;
+use crate::layout;
+use crate::memory::PeekPoke;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+// The display registers live near the bottom of memory, starting at 16:
+//   16: mode, see below
+//   17: screen, address of the screen data
+//   20: palette, address of 16 RGB332 colors for the paletted modes
+//   23: font, address of 256 8x8 glyphs, 8 bytes each, top row first, high bit on the left
+//   26: height of the screen data, in characters or pixels
+//   29: width of the screen data, in characters or pixels
+//   32: row_offset, the row of screen data shown at the top of the display
+//   35: col_offset, the column of screen data shown at the left
+//   38: back_screen, address of a second screen's data
+//   41: flip, bit 0 to show the back screen instead of the screen
+// Everything but the mode and flip is a 24-bit word. The screen data can be bigger
+// than the display and wraps around in both directions, so changing the
+// offsets scrolls. A program can draw into whichever screen isn't showing
+// and flip once it's done, so a frame never shows a half-drawn screen.
+//
+// The low three bits of the mode pick one of eight modes (see DisplayMode):
+//   1: graphics rather than text
+//   2: high resolution
+//   4: paletted rather than direct color
+//
+// Text modes are 8x8 characters, 40x30 with every pixel doubled in low res or
+// 80x60 in high res. Each character is two bytes: the glyph and its color.
+// In direct color the color byte is an RGB332 foreground on black; paletted,
+// its low nibble is the foreground palette index and the high nibble the
+// background.
+//
+// Graphics modes are a byte per pixel, either RGB332 or a palette index in
+// the low nibble. Low res is 128x128 with every pixel tripled, centered; high
+// res is 320x240 with every pixel doubled.
+//
+// Mode 8 is raw color: 128x128 like low res graphics, but each pixel is three
+// bytes of red, green, and blue copied straight to the output, for programs
+// that would rather spend the memory than live with eight-bit color. The
+// other mode bits don't matter when 8 is set.
+//
+// Over whatever the mode draws go eight sprites, described by a table at 64,
+// ten bytes apiece:
+//   0: x, the left edge in display pixels, 0-639
+//   3: y, the top edge in display pixels, 0-479
+//   6: tile, address of 64 bytes, one per pixel, row by row
+//   9: flags, bit 0 to show the sprite
+// A tile byte's low nibble is a palette index, except that 0 is transparent
+// and leaves what's underneath. Sprite pixels are doubled, so a sprite
+// covers 16x16 display pixels, and later sprites draw over earlier ones.
+
+/// The size of the rendered display in pixels, and of a default FrameBuffer
+pub const WIDTH: usize = 640;
+pub const HEIGHT: usize = 480;
+
+const SPRITE_COUNT: u32 = 8;
+const SPRITE_SIZE: u32 = 10; // Bytes in a sprite's descriptor
+const SPRITE_SCALE: u32 = 2;
+
+// The low res graphics modes are 128x128, each pixel tripled
+const LOW_RES: u32 = 128;
+const LOW_RES_SCALE: u32 = 3;
+
+// Where each register is
+const MODE: u32 = layout::DISPLAY_REGISTERS;
+const SCREEN: u32 = layout::DISPLAY_REGISTERS + 1;
+const PALETTE: u32 = layout::DISPLAY_REGISTERS + 4;
+const FONT_ADDR: u32 = layout::DISPLAY_REGISTERS + 7;
+const HEIGHT_REG: u32 = layout::DISPLAY_REGISTERS + 10;
+const WIDTH_REG: u32 = layout::DISPLAY_REGISTERS + 13;
+const ROW_OFFSET: u32 = layout::DISPLAY_REGISTERS + 16;
+const COL_OFFSET: u32 = layout::DISPLAY_REGISTERS + 19;
+const BACK_SCREEN: u32 = layout::DISPLAY_REGISTERS + 22;
+const FLIP: u32 = layout::DISPLAY_REGISTERS + 25;
+
+/// How many bytes the display registers take up, from DISPLAY_REGISTERS on
+pub const REGISTERS_SIZE: u32 = FLIP - MODE + 1;
+
+pub struct DisplayRegisters {
+    pub mode: u8,
+    pub screen: Word,
+    pub palette: Word,
+    pub font: Word,
+    pub height: u32,
+    pub width: u32,
+    pub row_offset: u32,
+    pub col_offset: u32,
+    pub back_screen: Word,
+    pub flip: bool,
+}
+
+impl DisplayRegisters {
+    pub fn read<P: PeekPoke>(machine: &P) -> Self {
+        Self {
+            mode: machine.peek(MODE.into()),
+            screen: machine.peek24(SCREEN.into()).into(),
+            palette: machine.peek24(PALETTE.into()).into(),
+            font: machine.peek24(FONT_ADDR.into()).into(),
+            // Zero would leave nothing for the offsets to wrap around in
+            height: machine.peek24(HEIGHT_REG.into()).max(1),
+            width: machine.peek24(WIDTH_REG.into()).max(1),
+            row_offset: machine.peek24(ROW_OFFSET.into()),
+            col_offset: machine.peek24(COL_OFFSET.into()),
+            back_screen: machine.peek24(BACK_SCREEN.into()).into(),
+            flip: machine.peek(FLIP.into()) & 1 != 0,
+        }
+    }
+
+    /// The screen being shown, which is the back screen when flipped
+    pub fn presented(&self) -> Word {
+        if self.flip { self.back_screen } else { self.screen }
+    }
+}
+
+const DEFAULT_PALETTE_COLORS: [u8; PALETTE_SIZE] = [
+    0x00, 0x02, 0x10, 0x12, 0x80, 0x82, 0x8c, 0xb6, // Dark colors
+    0x49, 0x03, 0x1c, 0x1f, 0xe0, 0xe3, 0xfc, 0xff, // Bright colors
+];
+
+// Daniel Hepper's public domain font8x8 (https://github.com/dhepper/font8x8),
+// from the legacy tables of the font8x8 crate 0.3.1 (MIT, copyright 2017
+// Joaquin Rosales), each row mirrored so the high bit is on the left. ASCII
+// comes first, then 32 blank control codes, then Latin-1 from 0xa0.
+const FONT: &[u8; FONT_SIZE] = include_bytes!("font.rom");
+
+/// Puts the display back how it is at power-on: low res direct color text,
+/// 40x30, with the screen at 0x10000 and the default palette and font
+/// copied in just below the top of memory.
+pub fn reset<P: PeekPoke>(machine: &mut P) {
+    machine.poke(MODE.into(), 0);
+    machine.poke24(SCREEN.into(), layout::DEFAULT_SCREEN);
+    machine.poke24(PALETTE.into(), layout::DEFAULT_PALETTE);
+    machine.poke24(FONT_ADDR.into(), layout::DEFAULT_FONT);
+    machine.poke24(HEIGHT_REG.into(), 30);
+    machine.poke24(WIDTH_REG.into(), 40);
+    machine.poke24(ROW_OFFSET.into(), 0);
+    machine.poke24(COL_OFFSET.into(), 0);
+    machine.poke24(BACK_SCREEN.into(), layout::DEFAULT_SCREEN);
+    machine.poke(FLIP.into(), 0);
+
+    for i in 0..SPRITE_COUNT {
+        machine.poke(Word::from(layout::SPRITES + i * SPRITE_SIZE + 9), 0)
+    }
+
+    load_palette(machine, &DEFAULT_PALETTE_COLORS).unwrap();
+    load_font(machine, FONT).unwrap();
+}
+
+pub const PALETTE_SIZE: usize = 16;
+pub const FONT_SIZE: usize = 2048;
+
+/// A palette or font that isn't the size it has to be
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct AssetLenError {
+    pub expected: usize,
+    pub actual: usize,
+}
+
+impl std::fmt::Display for AssetLenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expected {} bytes, got {}", self.expected, self.actual)
+    }
+}
+
+impl std::error::Error for AssetLenError {}
+
+/// Copies 16 RGB332 colors to wherever the palette register points
+pub fn load_palette<P: PeekPoke>(machine: &mut P, bytes: &[u8]) -> Result<(), AssetLenError> {
+    let addr = DisplayRegisters::read(machine).palette;
+    load_asset(machine, addr, bytes, PALETTE_SIZE)
+}
+
+/// Copies 256 8x8 glyphs, laid out like font.rom, to wherever the font
+/// register points
+pub fn load_font<P: PeekPoke>(machine: &mut P, bytes: &[u8]) -> Result<(), AssetLenError> {
+    let addr = DisplayRegisters::read(machine).font;
+    load_asset(machine, addr, bytes, FONT_SIZE)
+}
+
+/// The palette the palette register points at, in the form `load_palette`
+/// takes, for saving
+pub fn dump_palette<P: PeekPoke>(machine: &P) -> Vec<u8> {
+    dump_asset(machine, DisplayRegisters::read(machine).palette, PALETTE_SIZE)
+}
+
+/// The font the font register points at, in the form `load_font` takes
+pub fn dump_font<P: PeekPoke>(machine: &P) -> Vec<u8> {
+    dump_asset(machine, DisplayRegisters::read(machine).font, FONT_SIZE)
+}
+
+fn load_asset<P: PeekPoke>(machine: &mut P, addr: Word, bytes: &[u8], expected: usize) -> Result<(), AssetLenError> {
+    if bytes.len() != expected {
+        return Err(AssetLenError { expected, actual: bytes.len() })
+    }
+    machine.poke_slice(addr, bytes);
+    Ok(())
+}
+
+fn dump_asset<P: PeekPoke>(machine: &P, addr: Word, len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    machine.peek_slice(addr, &mut bytes);
+    bytes
+}
+
+/// How a FrameBuffer lays out each pixel's bytes
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum PixelFormat {
+    #[default]
+    Rgba, // What `pixels` wants
+    Bgra,
+    Rgb, // No alpha at all
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgba | PixelFormat::Bgra => 4,
+            PixelFormat::Rgb => 3,
+        }
+    }
+
+    // Writes an opaque color into one pixel's bytes
+    fn write(self, pixel: &mut [u8], [r, g, b]: [u8; 3]) {
+        match self {
+            PixelFormat::Rgba => pixel.copy_from_slice(&[r, g, b, 0xff]),
+            PixelFormat::Bgra => pixel.copy_from_slice(&[b, g, r, 0xff]),
+            PixelFormat::Rgb => pixel.copy_from_slice(&[r, g, b]),
+        }
+    }
+}
+
+/// An image to draw the display into, row by row, RGBA unless it's made
+/// with another format
+pub struct FrameBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    pub data: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self::with_format(width, height, PixelFormat::Rgba)
+    }
+
+    pub fn with_format(width: usize, height: usize, format: PixelFormat) -> Self {
+        Self { width, height, format, data: vec![0u8; width * height * format.bytes_per_pixel()] }
+    }
+
+    // Sets one pixel, if it's in the frame
+    fn set(&mut self, x: u32, y: u32, rgb: [u8; 3]) {
+        let (x, y) = (x as usize, y as usize);
+        if x < self.width && y < self.height {
+            let size = self.format.bytes_per_pixel();
+            let i = (y * self.width + x) * size;
+            self.format.write(&mut self.data[i..i + size], rgb)
+        }
+    }
+
+    // Sets every pixel to the color `rgb` gives for its x and y
+    fn fill<F: FnMut(u32, u32) -> [u8; 3]>(&mut self, mut rgb: F) {
+        let (width, format) = (self.width, self.format);
+        for (i, pixel) in self.data.chunks_exact_mut(format.bytes_per_pixel()).enumerate() {
+            format.write(pixel, rgb((i % width) as u32, (i / width) as u32))
+        }
+    }
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self { Self::new(WIDTH, HEIGHT) }
+}
+
+/// Which of the display modes the mode register selects
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DisplayMode {
+    DirectLowText,
+    DirectHighText,
+    PalettedLowText,
+    PalettedHighText,
+    DirectLowGfx,
+    DirectHighGfx,
+    PalettedLowGfx,
+    PalettedHighGfx,
+    Raw,
+}
+
+impl DisplayMode {
+    /// Decodes the mode register. Only the low four bits mean anything; the
+    /// high four are ignored, so any byte is some mode.
+    pub fn from_mode_byte(mode: u8) -> DisplayMode {
+        use DisplayMode::*;
+        if mode & 8 != 0 {
+            return Raw
+        }
+        match mode & 7 {
+            0 => DirectLowText,
+            1 => DirectLowGfx,
+            2 => DirectHighText,
+            3 => DirectHighGfx,
+            4 => PalettedLowText,
+            5 => PalettedLowGfx,
+            6 => PalettedHighText,
+            _ => PalettedHighGfx,
+        }
+    }
+
+    /// Which of the mode's own pixels the point (x, y) of the WIDTH x HEIGHT
+    /// display shows, clamped onto the mode's pixels. Text modes count in
+    /// pixels too, not characters: low res ones are 320x240.
+    pub fn to_mode_coords(self, x: u32, y: u32) -> (u32, u32) {
+        use DisplayMode::*;
+        let (x, y) = (x.min(WIDTH as u32 - 1), y.min(HEIGHT as u32 - 1));
+        match self {
+            DirectHighText | PalettedHighText => (x, y),
+            DirectLowText | PalettedLowText | DirectHighGfx | PalettedHighGfx => (x / 2, y / 2),
+            DirectLowGfx | PalettedLowGfx | Raw => {
+                let (left, top) = scaled_origin((LOW_RES, LOW_RES), LOW_RES_SCALE);
+                let scale = |n: u32, edge: i64| ((n as i64 - edge).max(0) as u32 / LOW_RES_SCALE).min(LOW_RES - 1);
+                (scale(x, left), scale(y, top))
+            }
+        }
+    }
+
+    // How many bytes of screen data make up a character or pixel
+    fn cell_size(self) -> u32 {
+        use DisplayMode::*;
+        match self {
+            Raw => 3,
+            DirectLowGfx | DirectHighGfx | PalettedLowGfx | PalettedHighGfx => 1,
+            _ => 2,
+        }
+    }
+
+    fn draw_fn<P: PeekPoke>(self) -> fn(&P, &DisplayRegisters, &mut FrameBuffer) {
+        use DisplayMode::*;
+        match self {
+            DirectLowText => draw_direct_low_text,
+            DirectHighText => draw_direct_high_text,
+            PalettedLowText => draw_paletted_low_text,
+            PalettedHighText => draw_paletted_high_text,
+            DirectLowGfx => draw_direct_low_gfx,
+            DirectHighGfx => draw_direct_high_gfx,
+            PalettedLowGfx => draw_paletted_low_gfx,
+            PalettedHighGfx => draw_paletted_high_gfx,
+            Raw => draw_raw,
+        }
+    }
+}
+
+/// The display registers as a device of their own, to map over
+/// DISPLAY_REGISTERS with a `Bus` so the display sees every write to them
+/// rather than reading them from memory once a frame. It keeps the mode
+/// decoded as it's written. The screen, palette and font can be anywhere, so
+/// they stay in memory, and drawing still needs the whole machine: pass the
+/// bus to `draw`, which reads these registers back through it.
+pub struct DisplayDevice {
+    registers: [u8; REGISTERS_SIZE as usize],
+    mode: DisplayMode,
+}
+
+impl Default for DisplayDevice {
+    fn default() -> Self {
+        Self { registers: [0; REGISTERS_SIZE as usize], mode: DisplayMode::from_mode_byte(0) }
+    }
+}
+
+impl DisplayDevice {
+    /// The mode as of the last write to the mode register
+    pub fn mode(&self) -> DisplayMode { self.mode }
+}
+
+impl PeekPoke for DisplayDevice {
+    fn peek(&self, addr: Word) -> u8 {
+        self.registers[u32::from(addr) as usize % self.registers.len()]
+    }
+
+    fn poke(&mut self, addr: Word, val: u8) {
+        let offset = u32::from(addr) as usize % self.registers.len();
+        self.registers[offset] = val;
+        if offset == 0 {
+            self.mode = DisplayMode::from_mode_byte(val)
+        }
+    }
+}
+
+// The registers keep their values across a reset like the memory they stand
+// in for; `reset` is what puts them back to their power-on values
+impl Device for DisplayDevice {
+    fn tick(&mut self) {}
+    fn reset(&mut self) {}
+}
+
+/// Renders the screen into `frame`. Pixels are the same size whatever the
+/// frame's size: a frame smaller than WIDTH x HEIGHT shows the top left of
+/// what a full one would, and a bigger one shows more of the screen data.
+pub fn draw<P: PeekPoke>(machine: &P, frame: &mut FrameBuffer) {
+    assert_eq!(frame.data.len(), frame.width * frame.height * frame.format.bytes_per_pixel());
+    let reg = DisplayRegisters::read(machine);
+    DisplayMode::from_mode_byte(reg.mode).draw_fn()(machine, &reg, frame);
+    draw_sprites(machine, &reg, frame)
+}
+
+/// Renders the screen the same as `draw` and saves it as a PNG, for
+/// snapshots of a display without a window to show it in
+pub fn render_to_png<P: PeekPoke>(machine: &P, path: &Path) -> std::io::Result<()> {
+    let mut frame = FrameBuffer::default();
+    draw(machine, &mut frame);
+
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, frame.width as u32, frame.height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&frame.data)?;
+    Ok(())
+}
+
+/// Decodes an RGB332 color, red in the top three bits and blue in the
+/// bottom two. Each channel keeps its bits at the top of the byte and the
+/// rest are zero, so full red or green is 0xe0 and full blue is 0xc0.
+pub fn rgb332_to_rgb888(byte: u8) -> [u8; 3] {
+    [byte & 0xe0, (byte << 3) & 0xe0, (byte << 6) & 0xc0]
+}
+
+// Where in memory the character or pixel at (x, y) on the display lives,
+// after scrolling. Characters are two bytes, raw pixels three, and graphics
+// pixels one.
+fn to_byte_address(reg: &DisplayRegisters, x: u32, y: u32) -> Word {
+    let size = DisplayMode::from_mode_byte(reg.mode).cell_size();
+    let row = (y + reg.row_offset) % reg.height;
+    let col = (x + reg.col_offset) % reg.width;
+    reg.presented() + row.wrapping_mul(reg.width).wrapping_add(col).wrapping_mul(size) as i32
+}
+
+// Whether the pixel at (x, y) within a character is lit
+fn glyph_bit<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, glyph: u8, x: u32, y: u32) -> bool {
+    let row = machine.peek(reg.font + (glyph as i32 * 8 + y as i32));
+    row & (0x80 >> x) != 0
+}
+
+fn draw_direct_low_text<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, frame: &mut FrameBuffer) {
+    frame.fill(|x, y| {
+        let addr = to_byte_address(reg, x / 16, y / 16);
+        let color = machine.peek(addr + 1);
+        let vb = if glyph_bit(machine, reg, machine.peek(addr), (x % 16) / 2, (y % 16) / 2) { color } else { 0 };
+        rgb332_to_rgb888(vb)
+    })
+}
+
+fn draw_direct_high_text<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, frame: &mut FrameBuffer) {
+    frame.fill(|x, y| {
+        let addr = to_byte_address(reg, x / 8, y / 8);
+        let color = machine.peek(addr + 1);
+        let vb = if glyph_bit(machine, reg, machine.peek(addr), x % 8, y % 8) { color } else { 0 };
+        rgb332_to_rgb888(vb)
+    })
+}
+
+fn draw_paletted_low_text<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, frame: &mut FrameBuffer) {
+    frame.fill(|x, y| {
+        let addr = to_byte_address(reg, x / 16, y / 16);
+        let color = machine.peek(addr + 1);
+        let index = if glyph_bit(machine, reg, machine.peek(addr), (x % 16) / 2, (y % 16) / 2) { color & 0xf } else { color >> 4 };
+        rgb332_to_rgb888(machine.peek(reg.palette + index as i32))
+    })
+}
+
+fn draw_paletted_high_text<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, frame: &mut FrameBuffer) {
+    frame.fill(|x, y| {
+        let addr = to_byte_address(reg, x / 8, y / 8);
+        let color = machine.peek(addr + 1);
+        let index = if glyph_bit(machine, reg, machine.peek(addr), x % 8, y % 8) { color & 0xf } else { color >> 4 };
+        rgb332_to_rgb888(machine.peek(reg.palette + index as i32))
+    })
+}
+
+fn draw_direct_low_gfx<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, frame: &mut FrameBuffer) {
+    blit_scaled(frame, (LOW_RES, LOW_RES), LOW_RES_SCALE, |x, y| {
+        rgb332_to_rgb888(machine.peek(to_byte_address(reg, x, y)))
+    })
+}
+
+fn draw_direct_high_gfx<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, frame: &mut FrameBuffer) {
+    frame.fill(|x, y| rgb332_to_rgb888(machine.peek(to_byte_address(reg, x / 2, y / 2))))
+}
+
+fn draw_paletted_low_gfx<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, frame: &mut FrameBuffer) {
+    blit_scaled(frame, (LOW_RES, LOW_RES), LOW_RES_SCALE, |x, y| {
+        let index = machine.peek(to_byte_address(reg, x, y)) & 0xf;
+        rgb332_to_rgb888(machine.peek(reg.palette + index as i32))
+    })
+}
+
+fn draw_paletted_high_gfx<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, frame: &mut FrameBuffer) {
+    frame.fill(|x, y| {
+        let index = machine.peek(to_byte_address(reg, x / 2, y / 2)) & 0xf;
+        rgb332_to_rgb888(machine.peek(reg.palette + index as i32))
+    })
+}
+
+fn draw_raw<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, frame: &mut FrameBuffer) {
+    blit_scaled(frame, (LOW_RES, LOW_RES), LOW_RES_SCALE, |x, y| {
+        let addr = to_byte_address(reg, x, y);
+        [machine.peek(addr), machine.peek(addr + 1), machine.peek(addr + 2)]
+    })
+}
+
+// Draws a `width` x `height` image, each pixel blown up to a `scale` x
+// `scale` square, centered on the WIDTH x HEIGHT display, with black around
+// it. `rgb` gives the color of the source pixel at (x, y).
+fn blit_scaled<F: Fn(u32, u32) -> [u8; 3]>(frame: &mut FrameBuffer, (width, height): (u32, u32), scale: u32, rgb: F) {
+    let (left, top) = scaled_origin((width, height), scale);
+    frame.fill(|x, y| {
+        let (x, y) = (x as i64 - left, y as i64 - top);
+        let inside = x >= 0 && y >= 0 && x < (width * scale) as i64 && y < (height * scale) as i64;
+        if inside { rgb(x as u32 / scale, y as u32 / scale) } else { [0, 0, 0] }
+    })
+}
+
+fn draw_sprites<P: PeekPoke>(machine: &P, reg: &DisplayRegisters, frame: &mut FrameBuffer) {
+    for i in 0..SPRITE_COUNT {
+        let desc = Word::from(layout::SPRITES + i * SPRITE_SIZE);
+        if machine.peek(desc + 9) & 1 == 0 {
+            continue
+        }
+        let (left, top) = (machine.peek24(desc), machine.peek24(desc + 3));
+        let tile = Word::from(machine.peek24(desc + 6));
+        for (ty, tx) in (0..8).flat_map(|ty| (0..8).map(move |tx| (ty, tx))) {
+            let index = machine.peek(tile + (ty * 8 + tx) as i32) & 0xf;
+            if index == 0 {
+                continue
+            }
+            let color = rgb332_to_rgb888(machine.peek(reg.palette + index as i32));
+            for (dy, dx) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                frame.set(left + tx * SPRITE_SCALE + dx, top + ty * SPRITE_SCALE + dy, color)
+            }
+        }
+    }
+}
+
+// Where the top left of a scaled, centered image lands on the display
+fn scaled_origin((width, height): (u32, u32), scale: u32) -> (i64, i64) {
+    ((WIDTH as i64 - (width * scale) as i64) / 2, (HEIGHT as i64 - (height * scale) as i64) / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn pixel_at(frame: &[u8], x: usize, y: usize) -> [u8; 4] {
+        let i = (y * WIDTH + x) * 4;
+        [frame[i], frame[i + 1], frame[i + 2], frame[i + 3]]
+    }
+
+    fn render(mem: &Memory) -> Vec<u8> {
+        let mut frame = FrameBuffer::default();
+        draw(mem, &mut frame);
+        frame.data
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        let reg = DisplayRegisters::read(&mem);
+        assert_eq!(reg.mode, 0);
+        assert_eq!(reg.screen, 0x10000.into());
+        assert_eq!((reg.width, reg.height), (40, 30));
+        assert_eq!(mem.peek_u32(0x1ff0f), 0xff); // Last palette entry is white
+        assert_eq!(mem.peek_u32(0x1f700 + b'A' as u32 * 8), 0b00110000); // Top row of an A
+    }
+
+    #[test]
+    fn test_display_device() {
+        use crate::bus::map;
+        let start = layout::DISPLAY_REGISTERS;
+        let mut bus = map(start..=start + REGISTERS_SIZE - 1, DisplayDevice::default()).and(Memory::default());
+        reset(&mut bus);
+        assert_eq!(bus.device().mode(), DisplayMode::DirectLowText);
+        assert_eq!(DisplayRegisters::read(&bus).screen, 0x10000.into());
+        assert_eq!(bus.rest().peek24(SCREEN.into()), 0); // The registers didn't go to memory
+
+        bus.poke_u32(MODE, 5);
+        assert_eq!(bus.device().mode(), DisplayMode::PalettedLowGfx);
+        bus.poke24_u32(MODE + 1, 0x12000); // The screen register, not the mode
+        assert_eq!(bus.device().mode(), DisplayMode::PalettedLowGfx);
+
+        // Drawing through the bus gets the registers from the device
+        bus.poke_u32(0x12000, 10); // Bright green, top left pixel
+        let mut frame = FrameBuffer::default();
+        draw(&bus, &mut frame);
+        assert_eq!(pixel_at(&frame.data, 128, 48), [0, 0xe0, 0, 0xff]);
+    }
+
+    #[test]
+    fn test_direct_low_text() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        mem.poke_u32(0x10000, b'A');
+        mem.poke_u32(0x10001, 0xe0); // Red
+
+        let frame = render(&mem);
+        assert_eq!(pixel_at(&frame, 0, 0), [0, 0, 0, 0xff]);
+        assert_eq!(pixel_at(&frame, 4, 0), [0xe0, 0, 0, 0xff]); // Doubled pixels
+        assert_eq!(pixel_at(&frame, 5, 1), [0xe0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn test_raw_mode() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        mem.poke_u32(16, 8);
+        mem.poke24_u32(26, 128);
+        mem.poke24_u32(29, 128);
+        mem.poke24_u32(0x10000, 0x563412); // (0, 0) is 12 34 56
+        mem.poke24_u32(0x10003, 0x8001fe); // (1, 0) is fe 01 80
+        mem.poke24_u32(0x10000 + 128 * 3, 0x0a0b0c); // (0, 1)
+
+        let frame = render(&mem);
+        for (x, y) in [(128, 48), (130, 48), (128, 50), (130, 50)] {
+            assert_eq!(pixel_at(&frame, x, y), [0x12, 0x34, 0x56, 0xff]);
+        }
+        assert_eq!(pixel_at(&frame, 131, 48), [0xfe, 0x01, 0x80, 0xff]);
+        assert_eq!(pixel_at(&frame, 128, 51), [0x0c, 0x0b, 0x0a, 0xff]);
+        assert_eq!(pixel_at(&frame, 127, 48), [0, 0, 0, 0xff]); // Border
+        assert_eq!(pixel_at(&frame, 128 + 384, 48), [0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn test_render_to_png() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        mem.poke_u32(0x10000, b'A');
+        mem.poke_u32(0x10001, 0xe0);
+
+        let path = std::env::temp_dir().join(format!("vulcan-test-{}.png", std::process::id()));
+        render_to_png(&mem, &path).unwrap();
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut decoded = vec![0u8; reader.output_buffer_size()];
+        reader.next_frame(&mut decoded).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!((reader.info().width, reader.info().height), (WIDTH as u32, HEIGHT as u32));
+        assert_eq!(decoded, render(&mem));
+    }
+
+    #[test]
+    fn test_load_palette() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        mem.poke24_u32(20, 0x8000); // Moved, so the copy has to follow the register
+        let palette: Vec<u8> = (0..16).map(|i| i * 3).collect();
+        assert_eq!(load_palette(&mut mem, &palette), Ok(()));
+
+        let reg = DisplayRegisters::read(&mem);
+        for (i, &color) in palette.iter().enumerate() {
+            assert_eq!(mem.peek(reg.palette + i as i32), color)
+        }
+        assert_eq!(dump_palette(&mem), palette);
+        assert_eq!(mem.peek_u32(0x1ff01), DEFAULT_PALETTE_COLORS[1]); // The old one's untouched
+
+        assert_eq!(load_palette(&mut mem, &[0; 15]), Err(AssetLenError { expected: 16, actual: 15 }));
+        assert_eq!(dump_palette(&mem), palette);
+    }
+
+    #[test]
+    fn test_load_font() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        assert_eq!(dump_font(&mem), FONT.to_vec());
+
+        let font = vec![0xaa; FONT_SIZE];
+        assert_eq!(load_font(&mut mem, &font), Ok(()));
+        assert_eq!(dump_font(&mem), font);
+        assert_eq!(load_font(&mut mem, &font[1..]), Err(AssetLenError { expected: FONT_SIZE, actual: FONT_SIZE - 1 }));
+    }
+
+    #[test]
+    fn test_flip() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        mem.poke_u32(16, 3); // High res direct color graphics
+        mem.poke24_u32(26, 240);
+        mem.poke24_u32(29, 320);
+        mem.poke24_u32(38, 0x4000);
+        mem.poke_u32(0x10000, 0xe0); // Red on the screen
+        mem.poke_u32(0x4000, 0x1c); // Green on the back screen
+
+        assert_eq!(DisplayRegisters::read(&mem).presented(), 0x10000.into());
+        assert_eq!(pixel_at(&render(&mem), 0, 0), [0xe0, 0, 0, 0xff]);
+
+        mem.poke_u32(41, 1);
+        assert_eq!(DisplayRegisters::read(&mem).presented(), 0x4000.into());
+        assert_eq!(pixel_at(&render(&mem), 0, 0), [0, 0xe0, 0, 0xff]);
+
+        mem.poke_u32(41, 2); // Only bit 0 counts
+        assert_eq!(pixel_at(&render(&mem), 0, 0), [0xe0, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn test_scrolling() {
+        // In every mode, scrolling by (3, 2) should look exactly like the
+        // screen data itself moved up two rows and left three columns
+        let (width, height) = (40, 30);
+        for mode in 0..=8u8 {
+            let size = DisplayMode::from_mode_byte(mode).cell_size() as usize;
+            let cell = |x: usize, y: usize| 0x10000 + ((y % height) * width + x % width) * size;
+
+            let mut scrolled = Memory::default();
+            reset(&mut scrolled);
+            scrolled.poke_u32(16, mode);
+            for i in 0..width * height * size {
+                scrolled.poke_u32(0x10000 + i as u32, (i * 37 + i / 5) as u8);
+            }
+            let mut moved = scrolled.clone();
+            scrolled.poke24_u32(32, 2);
+            scrolled.poke24_u32(35, 3);
+
+            for y in 0..height {
+                for x in 0..width {
+                    for b in 0..size {
+                        let byte = scrolled.peek_u32((cell(x + 3, y + 2) + b) as u32);
+                        moved.poke_u32((cell(x, y) + b) as u32, byte);
+                    }
+                }
+            }
+            assert!(render(&scrolled) == render(&moved), "mode {}", mode);
+        }
+    }
+
+    #[test]
+    fn test_row_offset_wraps() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        mem.poke_u32(16, 1); // Low res direct color graphics, one byte per pixel
+        let mut reg = DisplayRegisters::read(&mem);
+        reg.width = 10;
+        reg.height = 4;
+
+        // Scrolled so the bottom row is at the top, the next row down is row 0
+        reg.row_offset = 3;
+        assert_eq!(to_byte_address(&reg, 0, 0), 0x1001e.into());
+        assert_eq!(to_byte_address(&reg, 2, 1), 0x10002.into());
+
+        // Offsets past the height wrap all the way around
+        reg.row_offset = 9;
+        assert_eq!(to_byte_address(&reg, 0, 0), 0x1000a.into());
+        assert_eq!(to_byte_address(&reg, 0, 3), 0x10000.into());
+    }
+
+    #[test]
+    fn test_sprite() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        mem.poke_u32(16, 3); // High res direct color graphics, all black
+        let mut tile = [0u8; 64];
+        tile[0] = 1; // Top left is palette color 1
+        tile[2] = 0x1f; // Only the low nibble counts
+        tile[63] = 2; // As is the bottom right
+        mem.poke_slice(0x8000.into(), &tile);
+
+        let desc = layout::SPRITES;
+        mem.poke24_u32(desc, 100);
+        mem.poke24_u32(desc + 3, 50);
+        mem.poke24_u32(desc + 6, 0x8000);
+        mem.poke_u32(desc + 9, 1);
+        mem.poke24_u32(desc + SPRITE_SIZE + 6, 0x8000); // A second, switched off
+        let frame = render(&mem);
+
+        let color = |i: usize| {
+            let [r, g, b] = rgb332_to_rgb888(DEFAULT_PALETTE_COLORS[i]);
+            [r, g, b, 0xff]
+        };
+        let black = [0, 0, 0, 0xff];
+        for (x, y) in [(100, 50), (101, 50), (100, 51), (101, 51)] {
+            assert_eq!(pixel_at(&frame, x, y), color(1), "at ({}, {})", x, y)
+        }
+        assert_eq!(pixel_at(&frame, 104, 51), color(15));
+        assert_eq!(pixel_at(&frame, 102, 50), black); // Transparent
+        assert_eq!(pixel_at(&frame, 99, 50), black);
+        assert_eq!(pixel_at(&frame, 115, 65), color(2));
+        assert_eq!(pixel_at(&frame, 116, 66), black);
+        assert_eq!(pixel_at(&frame, 0, 0), black);
+
+        // Hanging off the edge is fine
+        mem.poke24_u32(desc, 635);
+        mem.poke24_u32(desc + 3, 475);
+        let frame = render(&mem);
+        assert_eq!(pixel_at(&frame, 639, 479), black);
+        assert_eq!(pixel_at(&frame, 635, 475), color(1));
+
+        reset(&mut mem);
+        assert_eq!(mem.peek_u32(desc + 9), 0);
+    }
+
+    #[test]
+    fn test_pixel_formats() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        mem.poke_u32(16, 3); // High res direct color graphics
+        mem.poke24_u32(26, 240);
+        mem.poke24_u32(29, 320);
+        mem.poke_u32(0x10000, 0xe3); // Magenta, (0xe0, 0, 0xc0)
+
+        let draw_in = |format| {
+            let mut frame = FrameBuffer::with_format(2, 1, format);
+            draw(&mem, &mut frame);
+            frame.data
+        };
+        assert_eq!(draw_in(PixelFormat::Rgba), vec![0xe0, 0, 0xc0, 0xff, 0xe0, 0, 0xc0, 0xff]);
+        assert_eq!(draw_in(PixelFormat::Bgra), vec![0xc0, 0, 0xe0, 0xff, 0xc0, 0, 0xe0, 0xff]);
+        assert_eq!(draw_in(PixelFormat::Rgb), vec![0xe0, 0, 0xc0, 0xe0, 0, 0xc0]);
+        assert_eq!(FrameBuffer::default().format, PixelFormat::Rgba);
+    }
+
+    #[test]
+    fn test_rgb332() {
+        assert_eq!(rgb332_to_rgb888(0x00), [0, 0, 0]);
+        assert_eq!(rgb332_to_rgb888(0xff), [0xe0, 0xe0, 0xc0]);
+        assert_eq!(rgb332_to_rgb888(0xae), [0xa0, 0x60, 0x80]); // 101 011 10
+        assert_eq!(rgb332_to_rgb888(0xe0), [0xe0, 0, 0]);
+        assert_eq!(rgb332_to_rgb888(0x1c), [0, 0xe0, 0]);
+        assert_eq!(rgb332_to_rgb888(0x03), [0, 0, 0xc0]);
+    }
+
+    #[test]
+    fn test_blit_scaled() {
+        let mut frame = FrameBuffer::default();
+        blit_scaled(&mut frame, (10, 20), 4, |x, y| if (x, y) == (3, 5) { [1, 2, 3] } else { [9, 9, 9] });
+
+        // 40x80 centered: the image starts at (300, 200) and the pixel 12 and 20 further in
+        let lit = |x: usize, y: usize| (312..316).contains(&x) && (220..224).contains(&y);
+        let inside = |x: usize, y: usize| (300..340).contains(&x) && (200..280).contains(&y);
+        for y in 190..290 {
+            for x in 290..350 {
+                let expected = if lit(x, y) { [1, 2, 3, 0xff] } else if inside(x, y) { [9, 9, 9, 0xff] } else { [0, 0, 0, 0xff] };
+                assert_eq!(pixel_at(&frame.data, x, y), expected, "at ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mode_coords() {
+        use DisplayMode::*;
+        assert_eq!(DirectHighText.to_mode_coords(639, 479), (639, 479));
+        assert_eq!(PalettedLowText.to_mode_coords(639, 479), (319, 239));
+        assert_eq!(DirectHighGfx.to_mode_coords(101, 51), (50, 25));
+        assert_eq!(DirectLowGfx.to_mode_coords(0, 0), (0, 0)); // In the border
+        assert_eq!(DirectLowGfx.to_mode_coords(128 + 3 * 10 + 2, 48 + 3 * 5), (10, 5));
+        assert_eq!(Raw.to_mode_coords(639, 479), (127, 127));
+        assert_eq!(DirectHighText.to_mode_coords(5000, 5000), (639, 479));
+    }
+
+    #[test]
+    fn test_display_modes() {
+        use DisplayMode::*;
+        let modes = [DirectLowText, DirectLowGfx, DirectHighText, DirectHighGfx,
+                     PalettedLowText, PalettedLowGfx, PalettedHighText, PalettedHighGfx];
+        for (byte, mode) in modes.into_iter().enumerate() {
+            assert_eq!(DisplayMode::from_mode_byte(byte as u8), mode);
+            assert_eq!(DisplayMode::from_mode_byte(byte as u8 | 0xf0), mode); // High bits ignored
+            assert_eq!(DisplayMode::from_mode_byte(byte as u8 | 8), Raw);
+        }
+    }
+
+    #[test]
+    fn test_small_frame() {
+        let mut mem = Memory::default();
+        reset(&mut mem);
+        mem.poke_u32(0x10000, b'A');
+        mem.poke_u32(0x10001, 0xe0);
+        let full = render(&mem);
+
+        let mut small = FrameBuffer::new(16, 16);
+        draw(&mem, &mut small);
+        for (x, y) in [(0, 0), (4, 0), (5, 1), (15, 3), (3, 15), (15, 15)] {
+            let i = (y * 16 + x) * 4;
+            assert_eq!(small.data[i..i + 4], pixel_at(&full, x, y), "at ({}, {})", x, y);
+        }
+    }
+}
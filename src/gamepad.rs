@@ -0,0 +1,142 @@
+use crate::address::Word;
+use crate::bus::Device;
+use crate::memory::PeekPoke;
+use std::collections::{HashMap, HashSet};
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode};
+
+// Register layout, relative to wherever the gamepad is mapped:
+//   0: buttons held, a combination of the bits below
+// So polling the pad is one `load`. There's no real pad to read; keys on the
+// host keyboard stand in for the buttons: by default the arrows, Z and X for
+// A and B, and return and space for start and select.
+
+pub const UP: u8 = 1;
+pub const DOWN: u8 = 2;
+pub const LEFT: u8 = 4;
+pub const RIGHT: u8 = 8;
+pub const A: u8 = 16;
+pub const B: u8 = 32;
+pub const START: u8 = 64;
+pub const SELECT: u8 = 128;
+
+pub struct Gamepad {
+    buttons: u8,
+    keys: HashMap<VirtualKeyCode, u8>, // Which button each host key stands for
+    held: HashSet<VirtualKeyCode>, // Bound keys that are down, so a button stays down while any of its keys is
+}
+
+impl Default for Gamepad {
+    fn default() -> Self {
+        use VirtualKeyCode as Key;
+        let keys = [(Key::Up, UP), (Key::Down, DOWN), (Key::Left, LEFT), (Key::Right, RIGHT),
+                    (Key::Z, A), (Key::X, B), (Key::Return, START), (Key::Space, SELECT)];
+        Self { buttons: 0, keys: keys.into_iter().collect(), held: HashSet::new() }
+    }
+}
+
+impl Gamepad {
+    pub fn buttons(&self) -> u8 { self.buttons }
+
+    pub fn button(&mut self, bit: u8, pressed: bool) {
+        if pressed { self.buttons |= bit } else { self.buttons &= !bit }
+    }
+
+    /// Makes `key` stand for the button `bit`, as well as any other keys
+    /// already bound to it
+    pub fn bind(&mut self, key: VirtualKeyCode, bit: u8) {
+        self.keys.insert(key, bit);
+    }
+
+    /// Lets go of every button, for when the window loses focus and the
+    /// keys held then won't be reported released
+    pub fn release_all(&mut self) {
+        self.held.clear();
+        self.buttons = 0
+    }
+
+    /// Feed a winit keyboard event in. Keys that aren't bound to a button
+    /// are ignored. With several keys bound to one button, it's released
+    /// only once all of them are.
+    pub fn handle_input(&mut self, input: &KeyboardInput) {
+        let key = match input.virtual_keycode {
+            Some(key) if self.keys.contains_key(&key) => key,
+            _ => return
+        };
+        if input.state == ElementState::Pressed {
+            self.held.insert(key);
+        } else {
+            self.held.remove(&key);
+        }
+        let bit = self.keys[&key];
+        let pressed = self.held.iter().any(|held| self.keys.get(held) == Some(&bit));
+        self.button(bit, pressed)
+    }
+}
+
+impl PeekPoke for Gamepad {
+    fn peek(&self, addr: Word) -> u8 {
+        match u32::from(addr) {
+            0 => self.buttons,
+            _ => 0
+        }
+    }
+
+    fn poke(&mut self, _addr: Word, _val: u8) {}
+}
+
+impl Device for Gamepad {
+    fn tick(&mut self) {}
+
+    // Bindings are the host's business, so they survive a reset
+    fn reset(&mut self) {
+        self.release_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[allow(deprecated)] // KeyboardInput::modifiers
+    fn input(key: VirtualKeyCode, state: ElementState) -> KeyboardInput {
+        KeyboardInput { scancode: 0, state, virtual_keycode: Some(key), modifiers: Default::default() }
+    }
+
+    #[test]
+    fn test_buttons() {
+        let mut pad = Gamepad::default();
+        pad.handle_input(&input(VirtualKeyCode::Up, ElementState::Pressed));
+        pad.handle_input(&input(VirtualKeyCode::Z, ElementState::Pressed));
+        assert_eq!(pad.peek_u32(0), UP | A);
+
+        pad.handle_input(&input(VirtualKeyCode::Up, ElementState::Released));
+        pad.handle_input(&input(VirtualKeyCode::Q, ElementState::Pressed)); // Not bound
+        assert_eq!(pad.peek_u32(0), A);
+
+        pad.bind(VirtualKeyCode::Q, SELECT);
+        pad.handle_input(&input(VirtualKeyCode::Q, ElementState::Pressed));
+        assert_eq!(pad.peek_u32(0), A | SELECT);
+
+        pad.reset();
+        assert_eq!(pad.peek_u32(0), 0);
+    }
+
+    #[test]
+    fn test_shared_button() {
+        let mut pad = Gamepad::default();
+        pad.bind(VirtualKeyCode::W, UP);
+        pad.handle_input(&input(VirtualKeyCode::Up, ElementState::Pressed));
+        pad.handle_input(&input(VirtualKeyCode::W, ElementState::Pressed));
+        pad.handle_input(&input(VirtualKeyCode::Up, ElementState::Released));
+        assert_eq!(pad.peek_u32(0), UP); // W still holds it down
+
+        pad.handle_input(&input(VirtualKeyCode::W, ElementState::Released));
+        assert_eq!(pad.peek_u32(0), 0);
+
+        pad.handle_input(&input(VirtualKeyCode::W, ElementState::Pressed));
+        pad.release_all();
+        assert_eq!(pad.peek_u32(0), 0);
+        pad.handle_input(&input(VirtualKeyCode::Up, ElementState::Released)); // Never pressed, still fine
+        assert_eq!(pad.peek_u32(0), 0);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::address::Word;
+use crate::bus::Device;
+use crate::memory::PeekPoke;
+use std::collections::VecDeque;
+use winit::event::{ElementState, KeyboardInput, VirtualKeyCode};
+
+// Register layout, relative to wherever the keyboard is mapped:
+//   0: modifier state, a combination of the SHIFT / CTRL / ALT bits
+//   1: kind of the event at the head of the queue (EVENT_NONE if it's empty)
+//   2: key code of that event (winit's VirtualKeyCode, as a byte)
+//   3: modifier state at the moment that event happened
+// Writing anything to register 1 pops the head event. So a program can
+// `loadw` a whole event from register 1 as kind | key << 8 | mods << 16.
+
+pub const SHIFT: u8 = 1;
+pub const CTRL: u8 = 2;
+pub const ALT: u8 = 4;
+
+pub const EVENT_NONE: u8 = 0;
+pub const EVENT_DOWN: u8 = 1;
+pub const EVENT_UP: u8 = 2;
+pub const EVENT_REPEAT: u8 = 3;
+
+// Events past this many are dropped until the program catches up
+const QUEUE_LENGTH: usize = 16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct KeyEvent {
+    pub kind: u8,
+    pub key: u8,
+    pub modifiers: u8,
+}
+
+pub struct Keyboard {
+    held: [bool; 256],
+    modifiers: u8,
+    queue: VecDeque<KeyEvent>,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self {
+            held: [false; 256],
+            modifiers: 0,
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Keyboard {
+    pub fn modifiers(&self) -> u8 { self.modifiers }
+
+    /// Record a key going down or up. A press of a key that's already held
+    /// is the host's auto-repeat, and is queued as EVENT_REPEAT.
+    pub fn key(&mut self, key: u8, pressed: bool) {
+        let was_held = self.held[key as usize];
+        self.held[key as usize] = pressed;
+        self.modifiers = self.held_modifiers();
+
+        let kind = match (pressed, was_held) {
+            (true, false) => EVENT_DOWN,
+            (true, true) => EVENT_REPEAT,
+            (false, _) => EVENT_UP,
+        };
+
+        if self.queue.len() < QUEUE_LENGTH {
+            self.queue.push_back(KeyEvent { kind, key, modifiers: self.modifiers })
+        }
+    }
+
+    /// Lets go of every held key, queuing an EVENT_UP for each, for when the
+    /// window loses focus: the host won't say when keys held then are
+    /// released, so modifiers would otherwise stay stuck on.
+    pub fn release_all(&mut self) {
+        for key in 0..=255 {
+            if self.held[key as usize] {
+                self.key(key, false)
+            }
+        }
+    }
+
+    /// Feed a winit keyboard event in. Keys winit can't identify are ignored.
+    pub fn handle_input(&mut self, input: &KeyboardInput) {
+        if let Some(keycode) = input.virtual_keycode {
+            self.key(keycode as u8, input.state == ElementState::Pressed)
+        }
+    }
+
+    fn held_modifiers(&self) -> u8 {
+        let held = |code: VirtualKeyCode| self.held[code as usize];
+        let mut mods = 0;
+        if held(VirtualKeyCode::LShift) || held(VirtualKeyCode::RShift) { mods |= SHIFT }
+        if held(VirtualKeyCode::LControl) || held(VirtualKeyCode::RControl) { mods |= CTRL }
+        if held(VirtualKeyCode::LAlt) || held(VirtualKeyCode::RAlt) { mods |= ALT }
+        mods
+    }
+}
+
+impl PeekPoke for Keyboard {
+    fn peek(&self, addr: Word) -> u8 {
+        let head = self.queue.front();
+        match u32::from(addr) {
+            0 => self.modifiers,
+            1 => head.map_or(EVENT_NONE, |e| e.kind),
+            2 => head.map_or(0, |e| e.key),
+            3 => head.map_or(0, |e| e.modifiers),
+            _ => 0
+        }
+    }
+
+    fn poke(&mut self, addr: Word, _val: u8) {
+        if u32::from(addr) == 1 {
+            self.queue.pop_front();
+        }
+    }
+}
+
+impl Device for Keyboard {
+    fn tick(&mut self) {}
+
+    fn reset(&mut self) {
+        *self = Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIFT_KEY: u8 = VirtualKeyCode::LShift as u8;
+    const A_KEY: u8 = VirtualKeyCode::A as u8;
+
+    #[test]
+    fn test_shift_modifier() {
+        let mut kb = Keyboard::default();
+        kb.key(SHIFT_KEY, true);
+        assert_eq!(kb.peek_u32(0), SHIFT);
+
+        kb.key(VirtualKeyCode::RShift as u8, true);
+        kb.key(SHIFT_KEY, false);
+        assert_eq!(kb.peek_u32(0), SHIFT); // Right shift still held
+
+        kb.key(VirtualKeyCode::RShift as u8, false);
+        assert_eq!(kb.peek_u32(0), 0);
+    }
+
+    #[test]
+    fn test_event_queue() {
+        let mut kb = Keyboard::default();
+        assert_eq!(kb.peek_u32(1), EVENT_NONE);
+
+        kb.key(SHIFT_KEY, true);
+        kb.key(A_KEY, true);
+        kb.key(A_KEY, true);
+        kb.key(A_KEY, false);
+
+        assert_eq!(kb.peek24_u32(1), (EVENT_DOWN as u32) | (SHIFT_KEY as u32) << 8 | (SHIFT as u32) << 16);
+        kb.poke_u32(1, 0);
+        assert_eq!(kb.peek24_u32(1), (EVENT_DOWN as u32) | (A_KEY as u32) << 8 | (SHIFT as u32) << 16);
+        kb.poke_u32(1, 0);
+        assert_eq!(kb.peek_u32(1), EVENT_REPEAT);
+        kb.poke_u32(1, 0);
+        assert_eq!(kb.peek_u32(1), EVENT_UP);
+        kb.poke_u32(1, 0);
+        assert_eq!(kb.peek_u32(1), EVENT_NONE);
+    }
+
+    #[test]
+    fn test_release_all() {
+        let mut kb = Keyboard::default();
+        kb.key(SHIFT_KEY, true);
+        kb.key(A_KEY, true);
+        kb.queue.clear();
+
+        // Focus goes elsewhere with both held, so their releases never arrive
+        kb.release_all();
+        assert_eq!(kb.peek_u32(0), 0);
+        assert_eq!(kb.queue, [KeyEvent { kind: EVENT_UP, key: A_KEY, modifiers: SHIFT },
+                              KeyEvent { kind: EVENT_UP, key: SHIFT_KEY, modifiers: 0 }]);
+
+        // Pressing A again is a fresh press, not a repeat
+        kb.key(A_KEY, true);
+        assert_eq!(kb.queue.back().map(|e| e.kind), Some(EVENT_DOWN));
+    }
+
+    #[test]
+    fn test_queue_overflow() {
+        let mut kb = Keyboard::default();
+        for _ in 0..(QUEUE_LENGTH * 2) {
+            kb.key(A_KEY, true)
+        }
+        assert_eq!(kb.queue.len(), QUEUE_LENGTH);
+
+        kb.reset();
+        assert_eq!(kb.peek_u32(1), EVENT_NONE);
+    }
+}
@@ -0,0 +1,54 @@
+// The memory map of a standard Vulcan machine. Everything but MEM_SIZE is an
+// address; the display ones are only where things start out, since a
+// program can point the display registers anywhere it likes.
+//
+//   0-3         free
+//   4-7         keyboard registers, see keyboard.rs
+//   8-14        mouse registers, see mouse.rs
+//   15          gamepad buttons, see gamepad.rs
+//   16-41       display registers, see display.rs
+//   64-143      sprite descriptors, also in display.rs
+//   256-1023    the data stack, growing up from 256, and the call stack,
+//               growing down from 1024, sharing the space between them
+//   1024-       programs, loaded and started at 1024
+//   0x10000-    the screen
+//   0x1f700-    the font, 2k of it
+//   0x1ff00-    the palette, 16 colors
+
+pub use crate::address::MEM_SIZE;
+
+/// The first of the keyboard's four registers
+pub const KEYBOARD: u32 = 4;
+
+/// The first of the mouse's seven registers
+pub const MOUSE: u32 = 8;
+
+/// The gamepad's one register
+pub const GAMEPAD: u32 = 15;
+
+/// The first of the display registers
+pub const DISPLAY_REGISTERS: u32 = 16;
+
+/// The sprite descriptor table
+pub const SPRITES: u32 = 64;
+
+/// The bottom of the data stack, where `dp` starts
+pub const DATA_STACK_BASE: u32 = 256;
+
+/// Just above the top of the call stack, where `sp` starts
+pub const CALL_STACK_BASE: u32 = 1024;
+
+/// Where programs are loaded and `pc` starts
+pub const ENTRY_POINT: u32 = 1024;
+
+/// Where `iv` points until a program sets it
+pub const INTERRUPT_VECTOR: u32 = 1024;
+
+/// Where the screen register points at power on
+pub const DEFAULT_SCREEN: u32 = 0x10000;
+
+/// Where the font register points at power on, the 2k just below the palette
+pub const DEFAULT_FONT: u32 = DEFAULT_PALETTE - 0x800;
+
+/// Where the palette register points at power on
+pub const DEFAULT_PALETTE: u32 = MEM_SIZE - 0x100;
@@ -0,0 +1,17 @@
+pub mod address;
+pub mod asm;
+pub mod audio;
+pub mod bus;
+pub mod clock;
+pub mod cpu;
+pub mod disassembler;
+pub mod display;
+pub mod gamepad;
+pub mod keyboard;
+pub mod layout;
+pub mod memory;
+pub mod mouse;
+pub mod opcodes;
+pub mod ram;
+pub mod rom;
+pub mod timer;
@@ -1,74 +1,420 @@
-mod memory;
-mod address;
-mod opcodes;
-mod cpu;
-mod bus;
-
 use winit::{
     event::{ Event, WindowEvent },
     event_loop::{ EventLoop, ControlFlow },
-    window::WindowBuilder,
-    dpi::LogicalSize
+    window::{Window, WindowBuilder},
+    dpi::{LogicalSize, PhysicalPosition}
 };
 
-use pixels::{Error, Pixels, SurfaceTexture};
-use rand::RngCore;
-use rand::prelude::ThreadRng;
-use std::time::{Instant, Duration};
-use pixels::wgpu::Instance;
-use std::convert::TryInto;
+#[cfg(all(feature = "pixels", not(feature = "softbuffer")))]
+use pixels::{Pixels, SurfaceTexture};
+#[cfg(feature = "softbuffer")]
+use softbuffer::GraphicsContext;
+use std::time::{Duration, Instant};
+use std::path::Path;
+use std::process::exit;
+use vulcan_emu::bus::{map, Bus};
+use vulcan_emu::clock::{ClockConfig, Limiter};
+use vulcan_emu::cpu::{CPU, State, StopReason};
+use vulcan_emu::display::{self, DisplayMode, DisplayRegisters, FrameBuffer};
+use vulcan_emu::gamepad::Gamepad;
+use vulcan_emu::keyboard::Keyboard;
+use vulcan_emu::layout::{self, ENTRY_POINT};
+use vulcan_emu::memory::{Memory, PeekPoke};
+use vulcan_emu::mouse::Mouse;
+
+const USAGE: &str = "Usage: vulcan-emu [--headless] [--cycles N] [--speed HZ] [--dump-memory FILE] [--png FILE] [--palette FILE] [--font FILE] [ROM]";
+
+struct Options {
+    rom_path: Option<String>,
+    headless: bool, // Run without a window, for CI and scripts
+    cycles: usize, // How long a headless run lasts
+    clock: ClockConfig, // How fast a windowed run goes; headless runs go flat out
+    dump_path: Option<String>, // Where a headless run writes memory when it's done
+    png_path: Option<String>, // Where a headless run saves the screen when it's done
+    palette_path: Option<String>, // Replaces the default palette
+    font_path: Option<String>, // Replaces the built in font
+}
+
+// Memory with the input devices mapped over it, where `layout` says they go
+type Machine = Bus<Keyboard, Bus<Mouse, Bus<Gamepad, Memory>>>;
+
+fn machine(memory: Memory) -> Machine {
+    map(layout::KEYBOARD..=layout::KEYBOARD + 3, Keyboard::default())
+        .and(map(layout::MOUSE..=layout::MOUSE + 6, Mouse::default())
+        .and(map(layout::GAMEPAD..=layout::GAMEPAD, Gamepad::default())
+        .and(memory)))
+}
+
+fn keyboard(machine: &mut Machine) -> &mut Keyboard { machine.device_mut() }
+
+fn mouse(machine: &mut Machine) -> &mut Mouse { machine.rest_mut().device_mut() }
+
+fn gamepad(machine: &mut Machine) -> &mut Gamepad { machine.rest_mut().rest_mut().device_mut() }
+
+// The memory under the devices
+fn memory(machine: &Machine) -> &Memory { machine.rest().rest().rest() }
 
 fn main() {
+    env_logger::init();
+    let options = parse_args(std::env::args().skip(1));
+
+    // With a ROM to run, start from clean memory; without one, show whatever
+    // random junk memory powers on with
+    let mut memory = match options.rom_path {
+        Some(_) => Memory::default(),
+        None => Memory::randomized(&mut rand::thread_rng())
+    };
+    display::reset(&mut memory);
+    if let Some(path) = &options.palette_path {
+        load_asset(path, |bytes| display::load_palette(&mut memory, bytes))
+    }
+    if let Some(path) = &options.font_path {
+        load_asset(path, |bytes| display::load_font(&mut memory, bytes))
+    }
+
+    if let Some(path) = &options.rom_path {
+        load_rom(&mut memory, path)
+    }
+    let mut cpu = CPU::new(machine(memory));
+    if options.rom_path.is_some() {
+        cpu.resume()
+    }
+
+    if options.headless {
+        run_headless(&mut cpu, &options);
+        return
+    }
+
     let event_loop = EventLoop::new();
 
-    let window = {
-        let size = LogicalSize::new(640, 480);
-        WindowBuilder::new()
+    let mut screen = {
+        let size = LogicalSize::new(display::WIDTH as u32, display::HEIGHT as u32);
+        let window = WindowBuilder::new()
             .with_title("Vulcan")
             .with_inner_size(size)
             .with_min_inner_size(size)
             .build(&event_loop)
-            .unwrap()
+            .unwrap();
+        Screen::new(window)
     };
 
-    let mut pixels = {
-        let surface_texture = SurfaceTexture::new(640, 480, &window);
-        Pixels::new(640, 480, surface_texture).unwrap()
-    };
+    let mut frame = FrameBuffer::default();
+    let mut limiter = Limiter::new(options.clock);
+    let started = Instant::now();
+    let mut next_frame = started;
 
     event_loop.run(move |event, _, control_flow| {
-        *control_flow = ControlFlow::Poll;
+        *control_flow = ControlFlow::WaitUntil(next_frame);
 
         match event {
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 window_id
-            } if window_id == window.id() => {
+            } if window_id == screen.window().id() => {
                 *control_flow = ControlFlow::Exit
             }
-            Event::MainEventsCleared => {
+            Event::WindowEvent {
+                event: WindowEvent::KeyboardInput { input, .. },
+                window_id
+            } if window_id == screen.window().id() => {
+                keyboard(cpu.memory_mut()).handle_input(&input);
+                gamepad(cpu.memory_mut()).handle_input(&input)
+            }
+            // Keys let go of elsewhere never come back up here
+            Event::WindowEvent {
+                event: WindowEvent::Focused(false),
+                window_id
+            } if window_id == screen.window().id() => {
+                keyboard(cpu.memory_mut()).release_all();
+                gamepad(cpu.memory_mut()).release_all()
+            }
+            Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                window_id
+            } if window_id == screen.window().id() => {
+                let (x, y) = screen.pixel_at(position);
+                mouse(cpu.memory_mut()).move_to(x, y)
+            }
+            Event::WindowEvent {
+                event: WindowEvent::MouseInput { state, button, .. },
+                window_id
+            } if window_id == screen.window().id() => {
+                mouse(cpu.memory_mut()).handle_input(state, button)
+            }
+            // Input wakes the loop up too, so only some of these are frames
+            Event::MainEventsCleared if Instant::now() >= next_frame => {
+                next_frame = (next_frame + limiter.config().frame_duration()).max(Instant::now());
+                *control_flow = ControlFlow::WaitUntil(next_frame);
+                let times = run_frame(&mut cpu, &mut limiter, started.elapsed(), &mut frame);
+                let mode = DisplayMode::from_mode_byte(DisplayRegisters::read(cpu.memory()).mode);
+                mouse(cpu.memory_mut()).set_mode(mode);
                 let start = Instant::now();
-                draw(pixels.get_frame());
-                let draw_time = Instant::now() - start;
-                pixels.render();
-                let total_time = Instant::now() - start;
-                println!("Tick took {} total, {} to draw", total_time.as_micros(), draw_time.as_micros());
+                screen.present(&frame);
+                log::trace!("Frame took {}us running the CPU, {}us drawing, {}us presenting",
+                            times.cpu.as_micros(), times.draw.as_micros(), start.elapsed().as_micros());
             }
             _ => {}
         }
     })
 }
 
-fn draw(frame: &mut [u8]) {
-    assert_eq!(frame.len(), 640 * 480 * 4);
-    let mut rng = rand::thread_rng();
+// What puts frames in the window: `pixels` on the GPU by default, or with the
+// softbuffer feature, plain copies on the CPU for machines wgpu won't run on
+#[cfg(not(any(feature = "pixels", feature = "softbuffer")))]
+compile_error!("the window needs the pixels or softbuffer feature to draw with");
+
+#[cfg(all(feature = "pixels", not(feature = "softbuffer")))]
+struct Screen {
+    window: Window,
+    pixels: Pixels,
+}
+
+#[cfg(all(feature = "pixels", not(feature = "softbuffer")))]
+impl Screen {
+    fn new(window: Window) -> Self {
+        let (width, height) = (display::WIDTH as u32, display::HEIGHT as u32);
+        let surface_texture = SurfaceTexture::new(width, height, &window);
+        let pixels = Pixels::new(width, height, surface_texture).unwrap();
+        Self { window, pixels }
+    }
+
+    fn window(&self) -> &Window { &self.window }
+
+    // Which display pixel a cursor position in the window is over. Off the
+    // edge of the display still counts, pinned to the nearest pixel.
+    fn pixel_at(&self, position: PhysicalPosition<f64>) -> (u32, u32) {
+        let (x, y) = self.pixels.window_pos_to_pixel((position.x as f32, position.y as f32))
+            .unwrap_or_else(|pos| self.pixels.clamp_pixel_pos(pos));
+        (x as u32, y as u32)
+    }
+
+    fn present(&mut self, frame: &FrameBuffer) {
+        self.pixels.get_frame().copy_from_slice(&frame.data);
+        self.pixels.render().unwrap()
+    }
+}
+
+#[cfg(feature = "softbuffer")]
+struct Screen {
+    context: GraphicsContext<Window>,
+    buffer: Vec<u32>, // The frame repacked the way softbuffer wants it
+}
+
+#[cfg(feature = "softbuffer")]
+impl Screen {
+    fn new(window: Window) -> Self {
+        // The context owns the window, so it can't outlive it
+        let context = unsafe { GraphicsContext::new(window) }.unwrap();
+        Self { context, buffer: vec![0; display::WIDTH * display::HEIGHT] }
+    }
+
+    fn window(&self) -> &Window { self.context.window() }
+
+    // The frame goes at the top left of the window unscaled, so logical
+    // window pixels are display pixels, pinned to the nearest one off the edge
+    fn pixel_at(&self, position: PhysicalPosition<f64>) -> (u32, u32) {
+        let position = position.to_logical::<f64>(self.window().scale_factor());
+        let pin = |n: f64, size: usize| (n.max(0.0) as u32).min(size as u32 - 1);
+        (pin(position.x, display::WIDTH), pin(position.y, display::HEIGHT))
+    }
+
+    fn present(&mut self, frame: &FrameBuffer) {
+        pack_pixels(frame, &mut self.buffer);
+        self.context.set_buffer(&self.buffer, frame.width as u16, frame.height as u16)
+    }
+}
+
+// softbuffer takes each pixel as a u32 of 0x00rrggbb
+#[cfg(feature = "softbuffer")]
+fn pack_pixels(frame: &FrameBuffer, buffer: &mut [u32]) {
+    for (packed, pixel) in buffer.iter_mut().zip(frame.data.chunks_exact(4)) {
+        *packed = u32::from_be_bytes([0, pixel[0], pixel[1], pixel[2]])
+    }
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Options {
+    let mut options = Options { rom_path: None, headless: false, cycles: 10_000_000, clock: ClockConfig::default(), dump_path: None, png_path: None, palette_path: None, font_path: None };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--headless" => options.headless = true,
+            "--cycles" => {
+                options.cycles = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => n,
+                    None => usage()
+                }
+            }
+            "--speed" => {
+                options.clock.cycles_per_second = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => n,
+                    None => usage()
+                }
+            }
+            "--dump-memory" => options.dump_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--png" => options.png_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--palette" => options.palette_path = Some(args.next().unwrap_or_else(|| usage())),
+            "--font" => options.font_path = Some(args.next().unwrap_or_else(|| usage())),
+            _ if arg.starts_with("--") || options.rom_path.is_some() => usage(),
+            _ => options.rom_path = Some(arg)
+        }
+    }
+    if options.headless && options.rom_path.is_none() {
+        usage()
+    }
+    options
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2)
+}
 
-    for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
-        let p = rng.next_u32();
-        let [low, mid, high, _] = p.to_le_bytes();
-        pixel[0] = low;
-        pixel[1] = mid;
-        pixel[2] = high;
-        pixel[3] = 0xff;
+// How long the parts of one frame took
+struct FrameTimes {
+    cpu: Duration,
+    draw: Duration,
+}
+
+// One frame of the machine, `elapsed` after it started: run the CPU for as
+// long as the limiter says is due, give it its vblank, and draw the display
+fn run_frame<M: PeekPoke>(cpu: &mut CPU<M>, limiter: &mut Limiter, elapsed: Duration, frame: &mut FrameBuffer) -> FrameTimes {
+    let start = Instant::now();
+    if let (_, StopReason::Fault(e)) = limiter.run(cpu, elapsed) {
+        log::error!("{} at {:06x}", e, cpu.pc())
+    }
+    cpu.vblank();
+    let cpu_time = start.elapsed();
+
+    let start = Instant::now();
+    display::draw(cpu.memory(), frame);
+    FrameTimes { cpu: cpu_time, draw: start.elapsed() }
+}
+
+// Runs for the given number of cycles, counted like the limiter counts them,
+// or until the CPU halts, with a vblank whenever the program waits for one,
+// since there's no screen to wait on
+fn run_headless(cpu: &mut CPU<Machine>, options: &Options) {
+    let budget = options.cycles as u64;
+    while cpu.cycle_count() < budget {
+        let (_, stop) = cpu.run_cycles(budget - cpu.cycle_count());
+        if let StopReason::Fault(e) = stop {
+            eprintln!("{} after {} cycles", e, cpu.cycle_count());
+            exit(1)
+        }
+        match cpu.state() {
+            State::WaitingForVblank => cpu.vblank(),
+            State::Halted => break,
+            State::Running => {}
+        }
+    }
+    log::info!("Ran {} cycles, ending {:?}", cpu.cycle_count(), cpu.state());
+
+    if let Some(path) = &options.dump_path {
+        if let Err(e) = std::fs::write(path, memory(cpu.memory()).dump()) {
+            eprintln!("Can't write {}: {}", path, e);
+            exit(1)
+        }
+    }
+
+    if let Some(path) = &options.png_path {
+        if let Err(e) = display::render_to_png(cpu.memory(), Path::new(path)) {
+            eprintln!("Can't write {}: {}", path, e);
+            exit(1)
+        }
     }
-}
\ No newline at end of file
+}
+
+// Loads the ROM at the entry point, where execution starts, or exits with an error
+fn load_rom(memory: &mut Memory, path: &str) {
+    let rom = match std::fs::read(path) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Can't read {}: {}", path, e);
+            exit(1)
+        }
+    };
+
+    if memory.load(ENTRY_POINT.into(), &rom) < rom.len() {
+        eprintln!("{} is {} bytes, too big to fit in memory at {}", path, rom.len(), ENTRY_POINT);
+        exit(1)
+    }
+}
+
+// Reads a palette or font file and hands it to `load`, or exits with an error
+fn load_asset<F>(path: &str, load: F) where F: FnOnce(&[u8]) -> Result<(), display::AssetLenError> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Can't read {}: {}", path, e);
+            exit(1)
+        }
+    };
+
+    if let Err(e) = load(&bytes) {
+        eprintln!("{}: {}", path, e);
+        exit(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vulcan_emu::asm::assemble;
+
+    #[test]
+    fn test_run_frame() {
+        // Puts a red A on the screen, then waits for vblank before halting
+        let program = assemble("push 0x41\npush 0x10000\nstore\npush 0xe0\npush 0x10001\nstore\nwaitv\nhlt").unwrap();
+        let mut cpu = CPU::boot(&program).unwrap();
+        display::reset(cpu.memory_mut());
+        let mut limiter = Limiter::new(ClockConfig { cycles_per_second: 6000, frames_per_second: 60 });
+        let mut frame = FrameBuffer::default();
+
+        run_frame(&mut cpu, &mut limiter, Duration::from_millis(1), &mut frame);
+        assert_eq!(cpu.state(), State::Running); // Hasn't started yet
+        assert_eq!(frame.data[16..20], [0, 0, 0, 0xff]);
+
+        run_frame(&mut cpu, &mut limiter, Duration::from_millis(20), &mut frame);
+        assert_eq!(cpu.state(), State::Running); // Its wait is over
+        assert_eq!(frame.data[16..20], [0xe0, 0, 0, 0xff]); // Pixel (4, 0), inside the A
+
+        run_frame(&mut cpu, &mut limiter, Duration::from_millis(40), &mut frame);
+        assert_eq!(cpu.state(), State::Halted);
+    }
+
+    #[test]
+    fn test_machine_devices() {
+        let program = assemble(&format!("push {}\nload\npush {}\nloadw\npush {}\nloadw\npush {}\nloadw\nhlt",
+                                        layout::GAMEPAD, layout::MOUSE, layout::MOUSE + 3, layout::KEYBOARD + 1)).unwrap();
+        let mut mem = Memory::default();
+        mem.load(ENTRY_POINT.into(), &program);
+        let mut cpu = CPU::new(machine(mem));
+        gamepad(cpu.memory_mut()).button(vulcan_emu::gamepad::UP, true);
+        mouse(cpu.memory_mut()).move_to(100, 50);
+        keyboard(cpu.memory_mut()).key(0x41, true);
+        let (x, y) = mouse(cpu.memory_mut()).position();
+
+        cpu.resume();
+        cpu.run(100);
+        let stack: Vec<u32> = cpu.data_stack().into_iter().map(u32::from).collect();
+        assert_eq!(stack, vec![vulcan_emu::gamepad::UP as u32, x, y, vulcan_emu::keyboard::EVENT_DOWN as u32 | 0x41 << 8]);
+        assert_eq!(memory(cpu.memory()).peek_u32(layout::GAMEPAD), 0); // Nothing reached the memory under it
+    }
+
+    // Making the window itself needs a display, which tests don't have
+    #[cfg(feature = "softbuffer")]
+    #[test]
+    fn test_pack_pixels() {
+        use vulcan_emu::memory::PeekPoke;
+        let mut mem = Memory::default();
+        display::reset(&mut mem);
+        mem.poke_u32(0x10000, b'A');
+        mem.poke_u32(0x10001, 0x1c); // Green
+        let mut frame = FrameBuffer::default();
+        display::draw(&mem, &mut frame);
+
+        let mut buffer = vec![0; display::WIDTH * display::HEIGHT];
+        pack_pixels(&frame, &mut buffer);
+        assert_eq!(buffer[0], 0);
+        assert_eq!(buffer[4], 0x00e000); // Pixel (4, 0), inside the A
+    }
+}
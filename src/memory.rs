@@ -1,30 +1,170 @@
 use rand::Rng;
 use crate::address::Word;
 use crate::address::MEM_SIZE;
+use crate::bus::Device;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{self, SeqAccess, Visitor};
 
+#[derive(Clone)]
 pub struct Memory([u8; MEM_SIZE as usize]);
 
 impl Default for Memory {
     fn default() -> Self { Self([0u8; MEM_SIZE as usize]) }
 }
 
-impl From<Word> for usize {
-    fn from(w: Word) -> Self {
-        let w: u32 = w.into();
-        (w & (MEM_SIZE-1)) as usize
+/// A memory image that isn't exactly MEM_SIZE bytes, with its actual length
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LenError(pub usize);
+
+impl std::fmt::Display for LenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Memory image is {} bytes, not {}", self.0, MEM_SIZE)
     }
 }
 
-impl<R: Rng> From<R> for Memory {
-    fn from(mut rng: R) -> Self {
+impl std::error::Error for LenError {}
+
+/// A slice write that would run past 0xffffff and wrap around to address 0
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct WrapError {
+    pub addr: Word,
+    pub len: usize,
+}
+
+impl std::fmt::Display for WrapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes at {:06x} would wrap around the top of the address space", self.len, self.addr)
+    }
+}
+
+impl std::error::Error for WrapError {}
+
+/// A program image too long to fit in memory where it's loaded
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ImageTooLarge {
+    pub addr: Word,
+    pub len: usize,
+}
+
+impl std::fmt::Display for ImageTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-byte image doesn't fit in memory at {:06x}, which ends at {:06x}", self.len, self.addr, MEM_SIZE)
+    }
+}
+
+impl std::error::Error for ImageTooLarge {}
+
+impl Memory {
+    /// The whole of memory as raw bytes, the simplest possible save state
+    pub fn dump(&self) -> &[u8] { &self.0 }
+
+    /// Memory with every byte zero, the same as `Memory::default()`
+    pub fn new_zeroed() -> Memory { Memory::default() }
+
+    /// Memory with every byte drawn from `rng`, the way a real machine's RAM
+    /// comes up at power on
+    pub fn randomized<R: Rng + ?Sized>(rng: &mut R) -> Memory {
         let mut mem = Memory::default();
-        for i in 0..(MEM_SIZE - 1) {
-            mem.0[i as usize] = rng.gen()
-        }
+        rng.fill(&mut mem.0[..]);
         mem
     }
+
+    /// Memory holding a dump made by `dump`, which must be exactly MEM_SIZE
+    /// bytes long
+    pub fn from_dump(bytes: &[u8]) -> Result<Memory, LenError> {
+        if bytes.len() != MEM_SIZE as usize {
+            return Err(LenError(bytes.len()))
+        }
+        let mut mem = Memory::default();
+        mem.0.copy_from_slice(bytes);
+        Ok(mem)
+    }
+
+    /// Zeroed memory with `image` copied in starting at `addr`, or an error
+    /// if it doesn't fit below MEM_SIZE. (This can't be a `TryFrom<&[u8]>`:
+    /// the blanket `From<R: Rng>` rules that out.)
+    pub fn with_image(addr: Word, image: &[u8]) -> Result<Memory, ImageTooLarge> {
+        if u32::from(addr) as usize + image.len() > MEM_SIZE as usize {
+            return Err(ImageTooLarge { addr, len: image.len() })
+        }
+        let mut mem = Memory::default();
+        mem.load(addr, image);
+        Ok(mem)
+    }
+
+    /// Sets `len` bytes from `addr` up to `val`. Addresses wrap the same way
+    /// they do for `poke_slice`.
+    pub fn fill(&mut self, addr: Word, len: usize, val: u8) {
+        for i in 0..len {
+            self[addr + i as i32] = val
+        }
+    }
+
+    /// Zeroes `len` bytes from `addr` up, like `fill` with 0
+    pub fn clear(&mut self, addr: Word, len: usize) { self.fill(addr, len, 0) }
+
+    /// Copies `image` into memory starting at `addr`. Anything that would run
+    /// past the top of memory is dropped rather than wrapping around to the
+    /// bottom; returns how many bytes were actually copied.
+    pub fn load(&mut self, addr: Word, image: &[u8]) -> usize {
+        let start = usize::from(addr);
+        let len = image.len().min(MEM_SIZE as usize - start);
+        self.0[start..start + len].copy_from_slice(&image[..len]);
+        len
+    }
+}
+
+// Serde doesn't do arrays this big, so memory goes out as one run of bytes
+impl Serialize for Memory {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
 }
 
+impl<'de> Deserialize<'de> for Memory {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl<'de> Visitor<'de> for BytesVisitor {
+            type Value = Memory;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "{} bytes of memory", MEM_SIZE)
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Memory, E> {
+                let mut mem = Memory::default();
+                if v.len() != mem.0.len() {
+                    return Err(E::invalid_length(v.len(), &self))
+                }
+                mem.0.copy_from_slice(v);
+                Ok(mem)
+            }
+
+            // Formats without a native byte string send a sequence instead
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Memory, A::Error> {
+                let mut mem = Memory::default();
+                for i in 0..mem.0.len() {
+                    mem.0[i] = seq.next_element()?.ok_or_else(|| de::Error::invalid_length(i, &self))?
+                }
+                if seq.next_element::<u8>()?.is_some() {
+                    return Err(de::Error::invalid_length(mem.0.len() + 1, &self))
+                }
+                Ok(mem)
+            }
+        }
+
+        deserializer.deserialize_bytes(BytesVisitor)
+    }
+}
+
+// Kept for existing callers; `Memory::randomized` says what it does
+impl<R: Rng> From<R> for Memory {
+    fn from(mut rng: R) -> Self { Memory::randomized(&mut rng) }
+}
+
+// Any word indexes memory: usize::from(Word) folds it onto MEM_SIZE, so a
+// guest reading past the end of RAM wraps like the hardware, not a panic
 impl std::ops::Index<Word> for Memory {
     type Output = u8;
     fn index(&self, index: Word) -> &Self::Output {
@@ -42,6 +182,16 @@ pub trait PeekPoke {
     fn peek(&self, addr: Word) -> u8;
     fn poke(&mut self, addr: Word, val: u8);
 
+    /// Two bytes, little-endian like `peek24`: the low byte is at `addr`
+    fn peek16(&self, addr: Word) -> u16 {
+        (self.peek(addr) as u16) | ((self.peek(addr + 1) as u16) << 8)
+    }
+
+    fn poke16(&mut self, addr: Word, val: u16) {
+        self.poke(addr, val as u8);
+        self.poke(addr + 1, (val >> 8) as u8);
+    }
+
     fn peek24(&self, addr: Word) -> u32 {
         (self.peek(addr) as u32)
             | ((self.peek(addr + 1) as u32) << 8)
@@ -54,6 +204,32 @@ pub trait PeekPoke {
         self.poke(addr + 2, (val >> 16) as u8);
     }
 
+    /// Writes `data` a byte at a time from `addr` up. Addresses wrap like
+    /// any other word, so bytes past 0xffffff land at 0 onwards.
+    fn poke_slice(&mut self, addr: Word, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            self.poke(addr + i as i32, byte)
+        }
+    }
+
+    /// Fills `buf` a byte at a time from `addr` up, wrapping past 0xffffff
+    /// to 0 the same way `poke_slice` does
+    fn peek_slice(&self, addr: Word, buf: &mut [u8]) {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.peek(addr + i as i32)
+        }
+    }
+
+    /// Like `poke_slice`, but refuses, writing nothing, if `data` would
+    /// wrap around the top of the address space
+    fn try_poke_slice(&mut self, addr: Word, data: &[u8]) -> Result<(), WrapError> {
+        if u32::from(addr) as usize + data.len() > 0x1000000 {
+            return Err(WrapError { addr, len: data.len() })
+        }
+        self.poke_slice(addr, data);
+        Ok(())
+    }
+
     fn peek_u32(&self, addr: u32) -> u8 { self.peek(addr.into()) }
     fn poke_u32(&mut self, addr: u32, val: u8) { self.poke(addr.into(), val) }
     fn peek24_u32(&mut self, addr: u32) -> u32 { self.peek24(addr.into()) }
@@ -61,8 +237,34 @@ pub trait PeekPoke {
 }
 
 impl PeekPoke for Memory {
-    fn peek(&self, addr: Word) -> u8 { self[addr.into()] }
-    fn poke(&mut self, addr: Word, val: u8) { self[addr.into()] = val; }
+    fn peek(&self, addr: Word) -> u8 { self[addr] }
+    fn poke(&mut self, addr: Word, val: u8) { self[addr] = val; }
+}
+
+// Any byte buffer will do as memory for a test that only needs a little:
+// addresses wrap around its length, the way Memory's wrap at MEM_SIZE, and an
+// empty one reads as zeroes.
+impl PeekPoke for &mut [u8] {
+    fn peek(&self, addr: Word) -> u8 {
+        match self.len() {
+            0 => 0,
+            len => self[u32::from(addr) as usize % len]
+        }
+    }
+
+    fn poke(&mut self, addr: Word, val: u8) {
+        if !self.is_empty() {
+            let i = u32::from(addr) as usize % self.len();
+            self[i] = val
+        }
+    }
+}
+
+// Plain RAM doesn't do anything over time, and keeps its contents across a
+// reset, but this lets it sit at the end of a chain of devices on a Bus.
+impl Device for Memory {
+    fn tick(&mut self) {}
+    fn reset(&mut self) {}
 }
 
 #[cfg(test)]
@@ -87,11 +289,191 @@ mod tests {
         assert_eq!(mem.peek_u32(12), 0x12);
         assert_eq!(mem.peek24(10.into()), 0x123456);
         assert_eq!(mem.peek24(11.into()), 0x001234);
+
+        mem.poke16(20.into(), 0x1234);
+        assert_eq!((mem.peek_u32(20), mem.peek_u32(21), mem.peek_u32(22)), (0x34, 0x12, 0));
+        assert_eq!(mem.peek16(20.into()), 0x1234);
+        assert_eq!(mem.peek16(21.into()), 0x0012);
+    }
+
+    #[test]
+    fn test_address_wrapping() {
+        let mut mem = Memory::default();
+        mem.poke_u32(0, 0xaa);
+        mem.poke_u32(0x1ffff, 0xbb);
+        assert_eq!(mem.peek_u32(0x1ffff + 1), 0xaa);
+        assert_eq!(mem.peek_u32(0xffffff), 0xbb);
+        assert_eq!(mem[Word::from(0x20005)], mem[Word::from(5)]);
+
+        mem.poke_u32(0x20001, 0xcc);
+        assert_eq!(mem.peek_u32(1), 0xcc);
+        mem[Word::from(0x3ffff)] = 0xdd;
+        assert_eq!(mem.peek_u32(0x1ffff), 0xdd);
+
+        // A word at the very top runs off the end onto the bottom
+        assert_eq!(mem.peek24(0x1fffe.into()), 0xaadd00);
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut mem = Memory::default();
+        mem.fill(0x100.into(), 4, 0xaa);
+        assert_eq!(&mem.dump()[0xff..0x106], &[0, 0xaa, 0xaa, 0xaa, 0xaa, 0, 0]);
+
+        mem.clear(0x101.into(), 2);
+        assert_eq!(&mem.dump()[0xff..0x106], &[0, 0xaa, 0, 0, 0xaa, 0, 0]);
+
+        mem.fill(0xfffffe.into(), 3, 0xbb); // Off the top and back to 0
+        assert_eq!(mem.peek_u32(0x1fffd), 0);
+        assert_eq!(&mem.dump()[0x1fffe..], &[0xbb, 0xbb]);
+        assert_eq!(&mem.dump()[..2], &[0xbb, 0]);
+    }
+
+    #[test]
+    fn test_slice_peek_poke() {
+        let mut mem = Memory::default();
+        let mut buf = vec![0u8; MEM_SIZE as usize];
+        let mut slice = buf.as_mut_slice();
+        for (addr, val) in [(0, 1), (35, 2), (0x1ffff, 3), (0x20004, 4), (0xffffff, 5)] {
+            mem.poke24_u32(addr, val * 0x10101);
+            slice.poke24_u32(addr, val * 0x10101);
+        }
+        assert_eq!(buf, mem.dump());
+
+        let mut small = [0u8; 4];
+        let mut slice = &mut small[..];
+        slice.poke24_u32(3, 0x030201);
+        assert_eq!(slice.peek24_u32(7), 0x030201);
+        assert_eq!(small, [2, 3, 0, 1]);
+        let mut empty: &mut [u8] = &mut [];
+        empty.poke_u32(0, 1);
+        assert_eq!(empty.peek_u32(0), 0);
+    }
+
+    #[test]
+    fn test_index_wraps() {
+        let mut mem = Memory::default();
+        for (i, addr) in [MEM_SIZE, MEM_SIZE + 1, 0x7fffff, 0xfffffe, 0xffffff].into_iter().enumerate() {
+            mem[Word::from(addr)] = i as u8 + 1;
+            assert_eq!(mem[Word::from(addr % MEM_SIZE)], i as u8 + 1, "at {:06x}", addr);
+        }
+        assert_eq!(&mem.dump()[..2], &[1, 2]);
+        assert_eq!(&mem.dump()[MEM_SIZE as usize - 2..], &[4, 5]);
+    }
+
+    #[test]
+    fn test_randomized() {
+        use rand::{rngs::StdRng, SeedableRng};
+        assert!(Memory::new_zeroed().dump().iter().all(|&b| b == 0));
+
+        let mems: Vec<Memory> = (0..8).map(|seed| Memory::randomized(&mut StdRng::seed_from_u64(seed))).collect();
+        assert!(mems.iter().any(|m| m.peek_u32(MEM_SIZE - 1) != 0));
+        assert!(mems.iter().any(|m| m.peek_u32(0) != 0));
+        assert_eq!(Memory::randomized(&mut StdRng::seed_from_u64(3)).dump(), mems[3].dump());
+    }
+
+    #[test]
+    fn test_with_image() {
+        let mut mem = Memory::with_image(0.into(), &[1, 2, 3]).unwrap();
+        assert_eq!(mem.peek24_u32(0), 0x030201);
+        assert!(mem.dump()[3..].iter().all(|&b| b == 0));
+
+        let mem = Memory::with_image(0x400.into(), &[0xaa]).unwrap();
+        assert_eq!((mem.peek_u32(0x3ff), mem.peek_u32(0x400)), (0, 0xaa));
+
+        let too_big = vec![0u8; MEM_SIZE as usize + 1];
+        let err = Memory::with_image(0.into(), &too_big).err().unwrap();
+        assert_eq!(err, ImageTooLarge { addr: 0.into(), len: too_big.len() });
+        assert_eq!(err.to_string(), "131073-byte image doesn't fit in memory at 000000, which ends at 020000");
+        assert!(Memory::with_image(0.into(), &too_big[1..]).is_ok());
+        assert!(Memory::with_image((MEM_SIZE - 1).into(), &[1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_from_rng_top_byte() {
+        use rand::{rngs::StdRng, SeedableRng};
+        // The top byte is the last one the RNG produces, not left at zero
+        for seed in 0..8 {
+            let mut expected = vec![0u8; MEM_SIZE as usize];
+            StdRng::seed_from_u64(seed).fill(&mut expected[..]);
+            let mem = Memory::from(StdRng::seed_from_u64(seed));
+            assert_eq!(mem.peek_u32(MEM_SIZE - 1), expected[MEM_SIZE as usize - 1]);
+        }
+        assert!((0..8).any(|seed| Memory::from(StdRng::seed_from_u64(seed)).peek_u32(MEM_SIZE - 1) != 0));
+    }
+
+    #[test]
+    fn test_load() {
+        let mut mem = Memory::default();
+        let program = [0x01, 0x02, 0x07, 0x56, 0x34, 0x12, 29 << 2];
+        assert_eq!(mem.load(1024.into(), &program), program.len());
+        for (offset, &byte) in program.iter().enumerate() {
+            assert_eq!(mem.peek_u32(1024 + offset as u32), byte)
+        }
+        assert_eq!(mem.peek_u32(1024 + program.len() as u32), 0);
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut mem = Memory::default();
+        mem.poke24_u32(0x1234, 0xabcdef);
+        let image = mem.dump().to_vec();
+        assert_eq!(image.len(), MEM_SIZE as usize);
+
+        mem.poke24_u32(0x1234, 0);
+        let restored = Memory::from_dump(&image).unwrap();
+        assert_eq!(restored.peek24(0x1234.into()), 0xabcdef);
+        assert_eq!(restored.dump(), &image[..]);
+
+        assert!(matches!(Memory::from_dump(&image[1..]), Err(LenError(n)) if n == MEM_SIZE as usize - 1));
+        assert!(Memory::from_dump(&[]).is_err());
+    }
+
+    #[test]
+    fn test_load_truncates() {
+        let mut mem = Memory::default();
+        assert_eq!(mem.load((MEM_SIZE - 2).into(), &[1, 2, 3, 4]), 2);
+        assert_eq!(mem.peek_u32(MEM_SIZE - 2), 1);
+        assert_eq!(mem.peek_u32(MEM_SIZE - 1), 2);
+        assert_eq!(mem.peek_u32(0), 0); // Didn't wrap
+    }
+
+    #[test]
+    fn test_poke_slice() {
+        let mut mem = Memory::default();
+        mem.poke_slice(10.into(), &[5, 6, 7]);
+        assert_eq!(mem.peek24(10.into()), 0x070605);
+    }
+
+    #[test]
+    fn test_slice_wrapping() {
+        let mut mem = Memory::default();
+        mem.poke_slice(0xfffffe.into(), &[1, 2, 3]);
+        assert_eq!(mem.peek_u32(0xfffffe), 1);
+        assert_eq!(mem.peek_u32(0xffffff), 2);
+        assert_eq!(mem.peek_u32(0), 3);
+
+        let mut buf = [0; 3];
+        mem.peek_slice(0xfffffe.into(), &mut buf);
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(mem.peek24(0xfffffe.into()), 0x030201);
+    }
+
+    #[test]
+    fn test_try_poke_slice() {
+        let mut mem = Memory::default();
+        assert_eq!(mem.try_poke_slice(0xfffffe.into(), &[1, 2, 3]), Err(WrapError { addr: 0xfffffe.into(), len: 3 }));
+        assert_eq!(mem.peek_u32(0xfffffe), 0); // Nothing written
+        assert_eq!(mem.peek_u32(0), 0);
+
+        assert_eq!(mem.try_poke_slice(0xfffffd.into(), &[1, 2, 3]), Ok(()));
+        assert_eq!(mem.peek24(0xfffffd.into()), 0x030201);
+        assert_eq!(mem.try_poke_slice(0.into(), &[]), Ok(()));
     }
 
     #[test]
     fn test_addressing_arrays() {
         let a: usize = Word::from(0xffffff).into();
-        assert_eq!(a, 0x01ffff as usize);
+        assert_eq!(a, 0x01ffff);
     }
 }
@@ -0,0 +1,122 @@
+use crate::address::Word;
+use crate::bus::Device;
+use crate::display::{self, DisplayMode};
+use crate::memory::PeekPoke;
+use winit::event::{ElementState, MouseButton};
+
+// Register layout, relative to wherever the mouse is mapped:
+//   0-2: x position, a little-endian word
+//   3-5: y position, a little-endian word
+//   6:   buttons held, a combination of the LEFT / RIGHT / MIDDLE bits
+// Positions are in the pixels of the current display mode, so they line up
+// with what the program draws: 0-127 in the low res graphics modes, 0-319 by
+// 0-239 in high res graphics and low res text, and so on. The host says what
+// the mode is with `set_mode`.
+
+pub const LEFT: u8 = 1;
+pub const RIGHT: u8 = 2;
+pub const MIDDLE: u8 = 4;
+
+#[derive(Default)]
+pub struct Mouse {
+    x: u32, // On the WIDTH x HEIGHT display, before scaling to the mode
+    y: u32,
+    buttons: u8,
+    mode: Option<DisplayMode>, // None until set, which reads as the power-on mode
+}
+
+impl Mouse {
+    pub fn buttons(&self) -> u8 { self.buttons }
+
+    /// The cursor position in the current mode's pixels
+    pub fn position(&self) -> (u32, u32) {
+        self.mode.unwrap_or(DisplayMode::DirectLowText).to_mode_coords(self.x, self.y)
+    }
+
+    pub fn set_mode(&mut self, mode: DisplayMode) { self.mode = Some(mode) }
+
+    /// Moves the cursor to (x, y) on the WIDTH x HEIGHT display, clamping
+    /// anything off the edge onto it
+    pub fn move_to(&mut self, x: u32, y: u32) {
+        self.x = x.min(display::WIDTH as u32 - 1);
+        self.y = y.min(display::HEIGHT as u32 - 1);
+    }
+
+    pub fn button(&mut self, bit: u8, pressed: bool) {
+        if pressed { self.buttons |= bit } else { self.buttons &= !bit }
+    }
+
+    /// Feed a winit mouse button event in. Buttons past the first three are
+    /// ignored.
+    pub fn handle_input(&mut self, state: ElementState, button: MouseButton) {
+        let bit = match button {
+            MouseButton::Left => LEFT,
+            MouseButton::Right => RIGHT,
+            MouseButton::Middle => MIDDLE,
+            MouseButton::Other(_) => return
+        };
+        self.button(bit, state == ElementState::Pressed)
+    }
+}
+
+impl PeekPoke for Mouse {
+    fn peek(&self, addr: Word) -> u8 {
+        let (x, y) = self.position();
+        match u32::from(addr) {
+            n @ 0..=2 => (x >> (8 * n)) as u8,
+            n @ 3..=5 => (y >> (8 * (n - 3))) as u8,
+            6 => self.buttons,
+            _ => 0
+        }
+    }
+
+    fn poke(&mut self, _addr: Word, _val: u8) {}
+}
+
+impl Device for Mouse {
+    fn tick(&mut self) {}
+
+    fn reset(&mut self) {
+        *self = Self::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_position() {
+        let mut mouse = Mouse::default();
+        mouse.move_to(101, 51);
+        assert_eq!((mouse.peek24_u32(0), mouse.peek24_u32(3)), (50, 25)); // Low res text
+
+        mouse.set_mode(DisplayMode::DirectHighText);
+        assert_eq!((mouse.peek24_u32(0), mouse.peek24_u32(3)), (101, 51));
+
+        mouse.set_mode(DisplayMode::PalettedLowGfx);
+        mouse.move_to(128 + 3 * 100, 48 + 3 * 20 + 1);
+        assert_eq!((mouse.peek24_u32(0), mouse.peek24_u32(3)), (100, 20));
+
+        mouse.move_to(10_000, 10_000);
+        assert_eq!(mouse.position(), (127, 127));
+        mouse.set_mode(DisplayMode::DirectHighText);
+        assert_eq!((mouse.peek24_u32(0), mouse.peek24_u32(3)), (639, 479));
+    }
+
+    #[test]
+    fn test_buttons() {
+        let mut mouse = Mouse::default();
+        mouse.handle_input(ElementState::Pressed, MouseButton::Left);
+        mouse.handle_input(ElementState::Pressed, MouseButton::Middle);
+        mouse.handle_input(ElementState::Pressed, MouseButton::Other(7));
+        assert_eq!(mouse.peek_u32(6), LEFT | MIDDLE);
+        mouse.handle_input(ElementState::Released, MouseButton::Left);
+        assert_eq!(mouse.peek_u32(6), MIDDLE);
+
+        mouse.poke_u32(6, 0xff); // Read only
+        assert_eq!(mouse.buttons(), MIDDLE);
+        mouse.reset();
+        assert_eq!(mouse.buttons(), 0);
+    }
+}
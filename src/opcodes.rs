@@ -1,5 +1,5 @@
 use std::convert::TryFrom;
-use std::fmt::{Display, Formatter};
+use std::fmt::{Display, Formatter, LowerHex};
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Opcode {
@@ -46,6 +46,226 @@ pub enum Opcode {
     Popr,
     Peekr,
     Debug,
+    Waitv,
+    Addc,
+    Subc,
+    Neg,
+    Nip,
+    Tuck,
+    Depth,
+    Incr,
+    Decr,
+    Mulhi,
+    Cycles,
+    Flags,
+    Setflags,
+}
+
+// Every opcode in encoding order; test_all checks nothing's missing
+const ALL: &[Opcode] = {
+    use Opcode::*;
+    &[
+        Nop, Add, Sub, Mul, Div, Mod,
+        Rand, And, Or, Xor, Not, Gt,
+        Lt, Agt, Alt, Lshift, Rshift, Arshift,
+        Pop, Dup, Swap, Pick, Rot, Jmp,
+        Jmpr, Call, Ret, Brz, Brnz, Hlt,
+        Load, Loadw, Store, Storew, Inton, Intoff,
+        Setiv, Sdp, Setsdp, Pushr, Popr, Peekr,
+        Debug, Waitv, Addc, Subc, Neg, Nip,
+        Tuck, Depth, Incr, Decr, Mulhi, Cycles,
+        Flags, Setflags,
+    ]
+};
+
+impl Opcode {
+    /// Every opcode, in order of encoding, for generating tables and
+    /// exhaustive tests
+    pub fn all() -> &'static [Opcode] { ALL }
+    /// The numeric encoding, the inverse of `Opcode::try_from`. Variants are
+    /// declared in encoding order, so this is just the discriminant.
+    pub fn to_u8(self) -> u8 { self as u8 }
+
+    /// The canonical lowercase name of the opcode, as an assembler spells it
+    pub fn mnemonic(&self) -> &'static str {
+        use Opcode::*;
+        match self {
+            Nop => "nop",
+            Add => "add",
+            Sub => "sub",
+            Mul => "mul",
+            Div => "div",
+            Mod => "mod",
+            Rand => "rand",
+            And => "and",
+            Or => "or",
+            Xor => "xor",
+            Not => "not",
+            Gt => "gt",
+            Lt => "lt",
+            Agt => "agt",
+            Alt => "alt",
+            Lshift => "lshift",
+            Rshift => "rshift",
+            Arshift => "arshift",
+            Pop => "pop",
+            Dup => "dup",
+            Swap => "swap",
+            Pick => "pick",
+            Rot => "rot",
+            Jmp => "jmp",
+            Jmpr => "jmpr",
+            Call => "call",
+            Ret => "ret",
+            Brz => "brz",
+            Brnz => "brnz",
+            Hlt => "hlt",
+            Load => "load",
+            Loadw => "loadw",
+            Store => "store",
+            Storew => "storew",
+            Inton => "inton",
+            Intoff => "intoff",
+            Setiv => "setiv",
+            Sdp => "sdp",
+            Setsdp => "setsdp",
+            Pushr => "pushr",
+            Popr => "popr",
+            Peekr => "peekr",
+            Debug => "debug",
+            Waitv => "waitv",
+            Addc => "addc",
+            Subc => "subc",
+            Neg => "neg",
+            Nip => "nip",
+            Tuck => "tuck",
+            Depth => "depth",
+            Incr => "incr",
+            Decr => "decr",
+            Mulhi => "mulhi",
+            Cycles => "cycles",
+            Flags => "flags",
+            Setflags => "setflags",
+        }
+    }
+
+    pub fn from_mnemonic(s: &str) -> Option<Opcode> {
+        use Opcode::*;
+        Some(match s {
+            "nop" => Nop,
+            "add" => Add,
+            "sub" => Sub,
+            "mul" => Mul,
+            "div" => Div,
+            "mod" => Mod,
+            "rand" => Rand,
+            "and" => And,
+            "or" => Or,
+            "xor" => Xor,
+            "not" => Not,
+            "gt" => Gt,
+            "lt" => Lt,
+            "agt" => Agt,
+            "alt" => Alt,
+            "lshift" => Lshift,
+            "rshift" => Rshift,
+            "arshift" => Arshift,
+            "pop" => Pop,
+            "dup" => Dup,
+            "swap" => Swap,
+            "pick" => Pick,
+            "rot" => Rot,
+            "jmp" => Jmp,
+            "jmpr" => Jmpr,
+            "call" => Call,
+            "ret" => Ret,
+            "brz" => Brz,
+            "brnz" => Brnz,
+            "hlt" => Hlt,
+            "load" => Load,
+            "loadw" => Loadw,
+            "store" => Store,
+            "storew" => Storew,
+            "inton" => Inton,
+            "intoff" => Intoff,
+            "setiv" => Setiv,
+            "sdp" => Sdp,
+            "setsdp" => Setsdp,
+            "pushr" => Pushr,
+            "popr" => Popr,
+            "peekr" => Peekr,
+            "debug" => Debug,
+            "waitv" => Waitv,
+            "addc" => Addc,
+            "subc" => Subc,
+            "neg" => Neg,
+            "nip" => Nip,
+            "tuck" => Tuck,
+            "depth" => Depth,
+            "incr" => Incr,
+            "decr" => Decr,
+            "mulhi" => Mulhi,
+            "cycles" => Cycles,
+            "flags" => Flags,
+            "setflags" => Setflags,
+            _ => return None
+        })
+    }
+}
+
+/// How many cycles an instruction takes: 3 for the ones that go out to
+/// memory, 2 for the ones that can change the flow of control, and 1 for
+/// everything else, plus 1 per byte of argument to fetch.
+pub fn cycle_cost(opcode: Opcode, arg_len: u8) -> u32 {
+    use Opcode::*;
+    let base = match opcode {
+        Load | Loadw | Store | Storew => 3,
+        Jmp | Jmpr | Call | Ret | Brz | Brnz => 2,
+        _ => 1
+    };
+    base + arg_len as u32
+}
+
+/// Broadly what an opcode does with the data stack, which decides how the
+/// CPU sets it up to execute
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OpCategory {
+    Binary, // Takes two operands, which the CPU pops before executing it
+    Unary, // Works on the top of the data stack itself
+    Nullary, // Takes nothing from the stack, only pushes
+    Control, // Changes where execution goes, or the CPU's own state
+}
+
+impl Opcode {
+    /// Which `OpCategory` this is. Every opcode is listed here, so a new
+    /// one can't be left out by accident.
+    pub fn category(self) -> OpCategory {
+        use Opcode::*;
+        match self {
+            Add | Sub | Mul | Div | Mod | And | Or | Xor | Gt | Lt | Agt | Alt |
+            Lshift | Rshift | Arshift | Swap | Brz | Brnz | Store | Storew | Setsdp |
+            Addc | Subc | Nip | Tuck | Mulhi => OpCategory::Binary,
+            Not | Pop | Dup | Pick | Rot | Load | Loadw | Pushr | Neg | Incr | Decr => OpCategory::Unary,
+            Nop | Rand | Sdp | Popr | Peekr | Depth | Cycles | Flags => OpCategory::Nullary,
+            Jmp | Jmpr | Call | Ret | Hlt | Inton | Intoff | Setiv | Debug | Waitv | Setflags => OpCategory::Control,
+        }
+    }
+}
+
+impl Display for Opcode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.mnemonic())
+    }
+}
+
+impl LowerHex for Opcode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        LowerHex::fmt(&self.to_u8(), f)
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(op: Opcode) -> Self { op.to_u8() }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -108,6 +328,19 @@ impl TryFrom<u8> for Opcode {
             40 => Popr,
             41 => Peekr,
             42 => Debug,
+            43 => Waitv,
+            44 => Addc,
+            45 => Subc,
+            46 => Neg,
+            47 => Nip,
+            48 => Tuck,
+            49 => Depth,
+            50 => Incr,
+            51 => Decr,
+            52 => Mulhi,
+            53 => Cycles,
+            54 => Flags,
+            55 => Setflags,
             other => return Err(InvalidOpcode(other))
         })
     }
@@ -118,3 +351,66 @@ fn test_decode() {
     assert_eq!(Opcode::try_from(18), Ok(Opcode::Pop));
     //assert_eq!(str::fmt("{}", Opcode::try_from(136).unwrap_err()), Err(InvalidOpcode(136)));
 }
+
+#[test]
+fn test_mnemonics() {
+    for &op in Opcode::all() {
+        assert_eq!(Opcode::from_mnemonic(op.mnemonic()), Some(op));
+        assert_eq!(op.to_string(), op.mnemonic());
+    }
+    assert_eq!(Opcode::Jmpr.to_string(), "jmpr");
+    assert_eq!(Opcode::from_mnemonic("JMPR"), None);
+    assert_eq!(Opcode::from_mnemonic("push"), None);
+}
+
+#[test]
+fn test_all() {
+    let decodable: Vec<Opcode> = (0..=255).filter_map(|n| Opcode::try_from(n).ok()).collect();
+    assert_eq!(Opcode::all(), decodable);
+    assert_eq!(Opcode::all().len(), 56);
+    assert!(Opcode::all().iter().enumerate().all(|(n, op)| op.to_u8() as usize == n));
+}
+
+#[test]
+fn test_category() {
+    let count = |category| Opcode::all().iter().filter(|op| op.category() == category).count();
+    assert_eq!(count(OpCategory::Binary), 26);
+    assert_eq!(count(OpCategory::Unary), 11);
+    assert_eq!(count(OpCategory::Nullary), 8);
+    assert_eq!(count(OpCategory::Control), 11);
+
+    assert_eq!(Opcode::Add.category(), OpCategory::Binary);
+    assert_eq!(Opcode::Brz.category(), OpCategory::Binary); // Condition and offset both come off the stack
+    assert_eq!(Opcode::Neg.category(), OpCategory::Unary);
+    assert_eq!(Opcode::Rand.category(), OpCategory::Nullary);
+    assert_eq!(Opcode::Jmp.category(), OpCategory::Control);
+}
+
+#[test]
+fn test_cycle_cost() {
+    assert_eq!(cycle_cost(Opcode::Nop, 0), 1);
+    assert_eq!(cycle_cost(Opcode::Nop, 3), 4);
+    assert_eq!(cycle_cost(Opcode::Add, 1), 2);
+    assert_eq!(cycle_cost(Opcode::Storew, 0), 3);
+    assert_eq!(cycle_cost(Opcode::Load, 2), 5);
+    assert_eq!(cycle_cost(Opcode::Brnz, 3), 5);
+    assert_eq!(cycle_cost(Opcode::Ret, 0), 2);
+}
+
+#[test]
+fn test_opcode_hex() {
+    assert_eq!(format!("{:x}", Opcode::Pop), "12");
+    assert_eq!(format!("{:#04x}", Opcode::Debug), "0x2a");
+}
+
+#[test]
+fn test_encode() {
+    for n in 0..=255u8 {
+        if let Ok(op) = Opcode::try_from(n) {
+            assert_eq!(op.to_u8(), n);
+            assert_eq!(Opcode::try_from(op.to_u8()), Ok(op));
+        }
+    }
+    assert_eq!(Opcode::Nop.to_u8(), 0);
+    assert_eq!(u8::from(Opcode::Debug), 42);
+}
@@ -0,0 +1,84 @@
+use crate::address::Word;
+use crate::bus::Device;
+use crate::memory::PeekPoke;
+
+/// Plain read-write memory backed by a host buffer, for mapping somewhere
+/// with a `Bus` and reading back what the program wrote: point the display's
+/// screen register at one to capture frames without a window. Addresses past
+/// the end wrap around, like `Rom`.
+pub struct RamDevice(Vec<u8>);
+
+impl RamDevice {
+    /// `len` bytes of zeroes
+    pub fn new(len: usize) -> Self {
+        Self(vec![0; len])
+    }
+
+    pub fn as_slice(&self) -> &[u8] { &self.0 }
+
+    pub fn into_inner(self) -> Vec<u8> { self.0 }
+
+    fn index(&self, addr: Word) -> Option<usize> {
+        match self.0.len() {
+            0 => None,
+            len => Some(u32::from(addr) as usize % len)
+        }
+    }
+}
+
+impl From<Vec<u8>> for RamDevice {
+    fn from(bytes: Vec<u8>) -> Self { Self(bytes) }
+}
+
+impl PeekPoke for RamDevice {
+    fn peek(&self, addr: Word) -> u8 {
+        self.index(addr).map_or(0, |i| self.0[i])
+    }
+
+    fn poke(&mut self, addr: Word, val: u8) {
+        if let Some(i) = self.index(addr) {
+            self.0[i] = val
+        }
+    }
+}
+
+impl Device for RamDevice {
+    fn tick(&mut self) {}
+    fn reset(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::bus::map;
+    use crate::cpu::{StopReason, CPU};
+    use crate::layout::ENTRY_POINT;
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_ram() {
+        let mut ram = RamDevice::from(vec![1, 2, 3]);
+        ram.poke24_u32(1, 0x060504);
+        assert_eq!(ram.as_slice(), &[6, 4, 5]); // Wraps around
+        assert_eq!(ram.peek_u32(4), 4);
+
+        let mut empty = RamDevice::new(0);
+        empty.poke_u32(0, 1);
+        assert_eq!(empty.peek_u32(0), 0);
+    }
+
+    #[test]
+    fn test_capture() {
+        let program = assemble("push 0xe0\npush 0x10000\nstore\npush 0x1c1c1c\npush 0x10004\nstorew\nhlt").unwrap();
+        let mut cpu = CPU::new(map(0x10000..=0x1000f, RamDevice::new(16)).and(Memory::default()));
+        for (i, &byte) in program.iter().enumerate() {
+            cpu.memory_mut().poke(Word::from(ENTRY_POINT) + i as i32, byte)
+        }
+        cpu.resume();
+
+        assert_eq!(cpu.run(100), (7, StopReason::Halted));
+        let screen = cpu.memory().device().as_slice();
+        assert_eq!(screen[..8], [0xe0, 0, 0, 0, 0x1c, 0x1c, 0x1c, 0]);
+    }
+}
@@ -0,0 +1,65 @@
+use crate::address::Word;
+use crate::bus::Device;
+use crate::memory::PeekPoke;
+use log::debug;
+
+/// Read-only memory, for mapping a boot ROM or font into the address space
+/// with a `Bus`. Addresses past the end wrap around to the start, the way a
+/// small chip on a big bus shows up several times over; writes are ignored.
+pub struct Rom(Box<[u8]>);
+
+impl Rom {
+    pub fn new<B: Into<Box<[u8]>>>(contents: B) -> Self {
+        Self(contents.into())
+    }
+
+    pub fn len(&self) -> usize { self.0.len() }
+
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+}
+
+impl PeekPoke for Rom {
+    fn peek(&self, addr: Word) -> u8 {
+        if self.0.is_empty() {
+            return 0
+        }
+        self.0[u32::from(addr) as usize % self.0.len()]
+    }
+
+    fn poke(&mut self, addr: Word, val: u8) {
+        debug!("Ignoring write of {:02x} to ROM at {:06x}", val, addr)
+    }
+}
+
+impl Device for Rom {
+    fn tick(&mut self) {}
+    fn reset(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_rom() {
+        let mut rom = Rom::new(vec![1, 2, 3]);
+        assert_eq!(rom.peek24_u32(0), 0x030201);
+        assert_eq!(rom.peek_u32(4), 2); // Wraps around
+        rom.poke_u32(0, 0xff);
+        assert_eq!(rom.peek_u32(0), 1);
+        assert_eq!(Rom::new(vec![]).peek_u32(5), 0);
+    }
+
+    #[test]
+    fn test_rom_on_bus() {
+        let mut bus = Bus::new(0x100, 0x103, Rom::new(vec![0xaa; 4]), Memory::default());
+        bus.poke24_u32(0xff, 0x123456); // Straddles the start of the ROM
+        bus.poke_u32(0x104, 0x77);
+        assert_eq!(bus.peek_u32(0xff), 0x56);
+        assert_eq!(bus.peek_u32(0x100), 0xaa);
+        assert_eq!(bus.peek_u32(0x101), 0xaa);
+        assert_eq!(bus.peek_u32(0x104), 0x77);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::address::Word;
+use crate::bus::Device;
+use crate::memory::PeekPoke;
+
+// Register layout, relative to wherever the timer is mapped:
+//   0-2: reload value, a little-endian word. Writing any byte of it also
+//        restarts the count from the new value; 0 stops the timer.
+//   3-5: current count, a little-endian word, also writable
+//   6:   1 if an interrupt is pending, else 0. Writing anything clears it.
+// Each tick counts down by one. The tick that reaches zero reloads the count
+// and raises an interrupt, so with a reload of N one fires every N ticks.
+
+#[derive(Default)]
+pub struct Timer {
+    reload: u32,
+    count: u32,
+    pending: bool,
+}
+
+impl Timer {
+    pub fn reload(&self) -> u32 { self.reload }
+    pub fn count(&self) -> u32 { self.count }
+
+    /// Whether the timer has fired since the interrupt was last taken; the
+    /// host can watch this and call `cpu.interrupt()` itself
+    pub fn interrupt_pending(&self) -> bool { self.pending }
+}
+
+// `val` written into byte `n` of a 24-bit word
+fn set_byte(word: u32, n: u32, val: u8) -> u32 {
+    (word & !(0xff << (8 * n)) | (val as u32) << (8 * n)) & 0xffffff
+}
+
+impl PeekPoke for Timer {
+    fn peek(&self, addr: Word) -> u8 {
+        match u32::from(addr) {
+            n @ 0..=2 => (self.reload >> (8 * n)) as u8,
+            n @ 3..=5 => (self.count >> (8 * (n - 3))) as u8,
+            6 => self.pending as u8,
+            _ => 0
+        }
+    }
+
+    fn poke(&mut self, addr: Word, val: u8) {
+        match u32::from(addr) {
+            n @ 0..=2 => {
+                self.reload = set_byte(self.reload, n, val);
+                self.count = self.reload
+            }
+            n @ 3..=5 => self.count = set_byte(self.count, n - 3, val),
+            6 => self.pending = false,
+            _ => {}
+        }
+    }
+}
+
+impl Device for Timer {
+    fn tick(&mut self) {
+        if self.count == 0 {
+            return
+        }
+        self.count -= 1;
+        if self.count == 0 {
+            self.count = self.reload;
+            self.pending = true
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::default()
+    }
+
+    fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer() {
+        let mut timer = Timer::default();
+        timer.poke24_u32(0, 5);
+        assert_eq!(timer.peek24_u32(3), 5);
+
+        for _ in 0..4 {
+            timer.tick()
+        }
+        assert!(!timer.interrupt_pending());
+        assert_eq!(timer.peek24_u32(3), 1);
+        timer.tick();
+        assert!(timer.interrupt_pending());
+        assert_eq!(timer.peek_u32(6), 1);
+        assert_eq!(timer.peek24_u32(3), 5); // Reloaded
+
+        assert!(timer.take_interrupt());
+        assert!(!timer.take_interrupt());
+        for _ in 0..5 {
+            timer.tick()
+        }
+        timer.poke_u32(6, 0); // Acknowledged by the program instead
+        assert!(!timer.interrupt_pending());
+    }
+
+    #[test]
+    fn test_stopped_timer() {
+        let mut timer = Timer::default();
+        for _ in 0..100 {
+            timer.tick()
+        }
+        assert!(!timer.interrupt_pending());
+
+        timer.poke24_u32(0, 0x10000);
+        assert_eq!(timer.reload(), 0x10000);
+        timer.poke24_u32(3, 1); // Cut the current count short
+        timer.tick();
+        assert!(timer.interrupt_pending());
+        assert_eq!(timer.count(), 0x10000);
+
+        timer.reset();
+        assert_eq!((timer.reload(), timer.count(), timer.interrupt_pending()), (0, 0, false));
+    }
+}